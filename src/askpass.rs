@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::DirBuilderExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::display;
+
+/// set for ssh's askpass runs of our own executable, pointing at the
+/// socket of the sshfuse process that should answer
+pub const SOCKET_ENV: &str = "SSHFUSE_ASKPASS_SOCKET";
+
+/// Makes sshfuse the `SSH_ASKPASS` program of the ssh processes it starts,
+/// so password and keyboard-interactive prompts reach the user instead of
+/// failing silently. ssh runs our executable with the prompt, which relays
+/// it over a unix socket back to this process. answers come from
+/// `--askpass-cmd` when given, otherwise from a prompt on the terminal.
+#[derive(Debug)]
+pub struct Askpass {
+    dir: PathBuf,
+    socket: PathBuf,
+}
+
+struct Prompter {
+    /// shell command printing the secret, instead of asking on the terminal
+    secret_cmd: Option<String>,
+    /// answers to password prompts, reused when the session reconnects
+    remembered: HashMap<String, String>,
+    /// which ssh process got each remembered answer
+    answered_for: HashMap<String, u32>,
+}
+
+impl Askpass {
+    pub fn start(secret_cmd: Option<String>) -> io::Result<Self> {
+        // a private directory keeps other users off the socket
+        let dir = std::env::temp_dir().join(format!("sshfuse-askpass-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let socket = dir.join("socket");
+        let listener = UnixListener::bind(&socket)?;
+
+        let prompter = Arc::new(Mutex::new(Prompter {
+            secret_cmd,
+            remembered: Default::default(),
+            answered_for: Default::default(),
+        }));
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let prompter = prompter.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &prompter) {
+                        println!("askpass: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { dir, socket })
+    }
+
+    /// points `cmd`, a ssh invocation, at us for its prompts
+    pub fn apply(&self, cmd: &mut Command) {
+        let exe = match std::env::current_exe() {
+            Ok(exe) => exe,
+            Err(_) => return,
+        };
+
+        cmd.env("SSH_ASKPASS", exe)
+            // OpenSSH 8.4+ uses askpass even with a terminal around
+            .env("SSH_ASKPASS_REQUIRE", "force")
+            .env(SOCKET_ENV, &self.socket);
+    }
+}
+
+impl Drop for Askpass {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// answers one request: `<ssh pid>\n<prompt>` gets `0\n<secret>`, or `1\n`
+/// when there's no answer
+fn serve(mut stream: UnixStream, prompter: &Mutex<Prompter>) -> io::Result<()> {
+    let mut request = String::new();
+    stream.read_to_string(&mut request)?;
+
+    let mut lines = request.splitn(2, '\n');
+    let ssh_pid = lines.next().and_then(|p| p.parse().ok()).unwrap_or(0);
+    let prompt = lines.next().unwrap_or("");
+
+    // one prompt at a time, and a reconnect waits for the first answer
+    let answer = prompter.lock().unwrap().answer(prompt, ssh_pid);
+
+    match answer {
+        Some(secret) => write!(stream, "0\n{}", secret),
+        None => writeln!(stream, "1"),
+    }
+}
+
+impl Prompter {
+    fn answer(&mut self, prompt: &str, ssh_pid: u32) -> Option<String> {
+        let reusable = is_password_prompt(prompt);
+
+        if reusable {
+            match self.remembered.get(prompt) {
+                // the same ssh asking again means the answer was wrong
+                Some(_) if self.answered_for.get(prompt) == Some(&ssh_pid) => {
+                    self.remembered.remove(prompt);
+                }
+                Some(secret) => {
+                    let secret = secret.clone();
+                    self.answered_for.insert(prompt.to_string(), ssh_pid);
+                    return Some(secret);
+                }
+                None => {}
+            }
+        }
+
+        let secret = match &self.secret_cmd {
+            Some(cmd) => run_secret_cmd(cmd, prompt),
+            None => display::suspend_spinners(|| prompt_tty(prompt)),
+        };
+
+        match secret {
+            Ok(secret) => {
+                if reusable {
+                    self.remembered.insert(prompt.to_string(), secret.clone());
+                    self.answered_for.insert(prompt.to_string(), ssh_pid);
+                }
+                Some(secret)
+            }
+            Err(e) => {
+                println!("askpass: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// passwords and key passphrases stay valid, unlike one time codes or
+/// host key confirmations
+fn is_password_prompt(prompt: &str) -> bool {
+    let prompt = prompt.to_lowercase();
+    prompt.contains("password") || prompt.contains("passphrase")
+}
+
+/// runs `cmd` with the prompt in `$SSHFUSE_PROMPT`; its output is the secret
+fn run_secret_cmd(cmd: &str, prompt: &str) -> io::Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("SSHFUSE_PROMPT", prompt)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            cmd, output.status
+        )));
+    }
+
+    let secret = String::from_utf8_lossy(&output.stdout);
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// asks on the controlling terminal, without echoing unless it's a
+/// yes/no question
+fn prompt_tty(prompt: &str) -> io::Result<String> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let echo = prompt.contains("(yes/no");
+
+    write!(tty, "\n{}", prompt)?;
+    tty.flush()?;
+
+    let fd = tty.as_raw_fd();
+    let saved = unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        termios
+    };
+
+    if !echo {
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        quiet.c_lflag |= libc::ECHONL;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &quiet) };
+    }
+
+    let mut line = String::new();
+    let read = BufReader::new(&tty).read_line(&mut line);
+
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    read?;
+
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// askpass mode: relays `prompt` to the sshfuse process listening on
+/// `socket` and prints the answer for ssh. returns the exit status
+pub fn client(socket: &Path, prompt: &str) -> i32 {
+    let ssh_pid = std::os::unix::process::parent_id();
+
+    match ask(socket, prompt, ssh_pid) {
+        Ok(Some(secret)) => {
+            println!("{}", secret);
+            0
+        }
+        Ok(None) => 1,
+        Err(e) => {
+            eprintln!("sshfuse askpass: {}", e);
+            1
+        }
+    }
+}
+
+fn ask(socket: &Path, prompt: &str, ssh_pid: u32) -> io::Result<Option<String>> {
+    let mut stream = UnixStream::connect(socket)?;
+    write!(stream, "{}\n{}", ssh_pid, prompt)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    match reply.strip_prefix("0\n") {
+        Some(secret) => Ok(Some(secret.to_string())),
+        None => Ok(None),
+    }
+}
+
+#[test]
+fn test_askpass() {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::testing::TempTree;
+
+    let dir = TempTree::new("askpass-log");
+    let log = dir.join("log");
+
+    // logs each prompt it answers
+    let cmd = format!(
+        "echo \"$SSHFUSE_PROMPT\" >> '{}'; echo hunter2",
+        log.display()
+    );
+    let askpass = Askpass::start(Some(cmd)).unwrap();
+    assert_eq!(
+        fs::metadata(&askpass.dir).unwrap().permissions().mode() & 0o777,
+        0o700
+    );
+
+    let prompts = || fs::read_to_string(&log).unwrap_or_default().lines().count();
+
+    let password = "me@host's password: ";
+    assert_eq!(
+        ask(&askpass.socket, password, 100).unwrap().as_deref(),
+        Some("hunter2")
+    );
+    assert_eq!(prompts(), 1);
+
+    // a reconnect reuses the password
+    assert_eq!(
+        ask(&askpass.socket, password, 101).unwrap().as_deref(),
+        Some("hunter2")
+    );
+    assert_eq!(prompts(), 1);
+
+    // but the same ssh asking again means it was wrong
+    ask(&askpass.socket, password, 101).unwrap();
+    assert_eq!(prompts(), 2);
+
+    // one time codes are never reused
+    ask(&askpass.socket, "Verification code: ", 102).unwrap();
+    ask(&askpass.socket, "Verification code: ", 103).unwrap();
+    assert_eq!(prompts(), 4);
+
+    let socket = askpass.socket.clone();
+    drop(askpass);
+    assert!(!socket.exists());
+}
@@ -0,0 +1,150 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+/// A remote program printing the checksum of its stdin, which is computed
+/// again locally to tell the contents arrived intact
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Checksum {
+    /// GNU coreutils, busybox
+    Sha256Sum,
+    /// perl's, on macOS and the BSDs
+    Shasum,
+    Md5Sum,
+}
+
+/// remote command printing which checksum programs the remote has, one
+/// per line, best first
+pub const DETECT_CMD: &str =
+    "for c in sha256sum shasum md5sum; do command -v $c >/dev/null 2>&1 && echo $c; done";
+
+impl Checksum {
+    /// the first program `DETECT_CMD` found
+    pub fn pick(detected: &str) -> Option<Self> {
+        detected.lines().find_map(|l| match l.trim() {
+            "sha256sum" => Some(Checksum::Sha256Sum),
+            "shasum" => Some(Checksum::Shasum),
+            "md5sum" => Some(Checksum::Md5Sum),
+            _ => None,
+        })
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            Checksum::Sha256Sum => "sha256sum",
+            Checksum::Shasum => "shasum -a 256",
+            Checksum::Md5Sum => "md5sum",
+        }
+    }
+
+    fn hex_len(self) -> usize {
+        match self {
+            Checksum::Sha256Sum | Checksum::Shasum => 64,
+            Checksum::Md5Sum => 32,
+        }
+    }
+
+    /// lowercase hex digest of `data`, as the remote program prints it
+    pub fn digest(self, data: &[u8]) -> String {
+        let bytes = match self {
+            Checksum::Sha256Sum | Checksum::Shasum => Sha256::digest(data).to_vec(),
+            Checksum::Md5Sum => Md5::digest(data).to_vec(),
+        };
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// wraps remote command `cmd` so the checksum of what `read_cmd`
+    /// prints follows its output. `cmd` is `read_cmd`, maybe compressed.
+    /// the file is read a second time for the checksum, so one changing
+    /// during the transfer shows up as a mismatch too
+    pub fn verified_cmd(self, cmd: &str, read_cmd: &str) -> String {
+        format!(
+            "{}; s=$?; if [ $s = 0 ]; then {} 2>/dev/null | {}; fi; exit $s",
+            cmd,
+            read_cmd,
+            self.program()
+        )
+    }
+
+    /// splits the output of `verified_cmd` into the contents and the
+    /// checksum printed after them: `<hex>  -\n`
+    pub fn split_output(self, output: &[u8]) -> Option<(&[u8], String)> {
+        let trailer_len = self.hex_len() + 4;
+        if output.len() < trailer_len || !output.ends_with(b"  -\n") {
+            return None;
+        }
+
+        let (contents, trailer) = output.split_at(output.len() - trailer_len);
+        let digest = std::str::from_utf8(&trailer[..self.hex_len()]).ok()?;
+        if !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        Some((contents, digest.to_lowercase()))
+    }
+}
+
+#[test]
+fn test_checksum() {
+    use std::process::Command;
+
+    assert_eq!(
+        Checksum::pick("/usr/bin/nothing\nmd5sum\n"),
+        Some(Checksum::Md5Sum)
+    );
+    assert_eq!(
+        Checksum::pick("sha256sum\nmd5sum\n"),
+        Some(Checksum::Sha256Sum)
+    );
+    assert_eq!(Checksum::pick(""), None);
+
+    assert_eq!(
+        Checksum::Md5Sum.digest(b"contents"),
+        "98bf7d8c15784f0a3d63204441e1e2aa"
+    );
+    assert_eq!(
+        Checksum::Sha256Sum.digest(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+
+    let mut output = b"no newline at the end".to_vec();
+    output.extend_from_slice(b"98bf7d8c15784f0a3d63204441e1e2aa  -\n");
+    assert_eq!(
+        Checksum::Md5Sum.split_output(&output),
+        Some((
+            &b"no newline at the end"[..],
+            "98bf7d8c15784f0a3d63204441e1e2aa".to_string()
+        ))
+    );
+    assert_eq!(Checksum::Md5Sum.split_output(b"truncated"), None);
+
+    // against the real thing, when installed
+    let found = Command::new("sh")
+        .arg("-c")
+        .arg(DETECT_CMD)
+        .output()
+        .unwrap();
+    let checksum = match Checksum::pick(&String::from_utf8_lossy(&found.stdout)) {
+        Some(checksum) => checksum,
+        None => return,
+    };
+
+    let read = "printf 'some contents'";
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(checksum.verified_cmd(read, read))
+        .output()
+        .unwrap();
+    let (contents, digest) = checksum.split_output(&output.stdout).unwrap();
+    assert_eq!(contents, b"some contents");
+    assert_eq!(digest, checksum.digest(contents));
+
+    // no checksum when the read fails, and its status is kept
+    let read = "cat /nonexistent";
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(checksum.verified_cmd(read, read))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
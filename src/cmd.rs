@@ -1,87 +1,1457 @@
 use std::{
-    io::ErrorKind,
-    process::{Command, Output},
-    str,
+    collections::HashMap,
+    os::unix::process::ExitStatusExt,
+    process::{Command, ExitStatus, Output},
+    sync::{
+        atomic::{AtomicI64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use crate::ls::{parse_long_list, FileMeta};
+use rand::Rng;
+
+use crate::checksum::{self, Checksum};
+use crate::compress::{self, Codec, CompressMode, Compression};
+use crate::error::RunnerError;
+use crate::ls::{
+    parse_find_printf, parse_find_tree, parse_recursive_list, parse_stat, parse_utc_offset,
+    read_long_list, unreadable_lines, Dialect, FileMeta, ListFormat, DIALECTS, FIND_FIELDS,
+    FIND_PRINTF,
+};
+use crate::process::{self, Deadline};
+use crate::shell;
+use crate::stats::STATS;
+use crate::transport::Transport;
 
 pub trait CmdRunner: Send + Sync {
-    fn fetch_path(&self, path: &str) -> Option<Vec<FileMeta>>;
-    fn fetch_file(&self, path: &str) -> Output;
+    /// lists the entries of directory `path`
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError>;
+    /// reads the whole contents of file `path`
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError>;
+    /// reads up to `len` bytes of file `path` starting at `offset`.
+    /// less is returned at the end of the file
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError>;
+    /// lists several directories, in as few round trips as the runner can.
+    /// each gets its own result, keyed by path
+    fn fetch_paths(&self, paths: &[&str]) -> Listings {
+        paths
+            .iter()
+            .map(|p| (p.to_string(), self.fetch_path(p)))
+            .collect()
+    }
+    /// lists directory `path` and those under it, `depth` levels deep,
+    /// stopping once about `max_entries` entries were listed. keyed by
+    /// path without trailing slash, only directories listed in full are in
+    fn fetch_tree(&self, path: &str, depth: u32, max_entries: usize) -> Listings {
+        fetch_tree_by_level(self, path, depth, max_entries)
+    }
+    /// the metadata of `path` alone, whatever it is. `CommandMissing`
+    /// when the runner can't stat, the parent has to be listed instead
+    fn fetch_meta(&self, _path: &str) -> Result<FileMeta, RunnerError> {
+        Err(RunnerError::CommandMissing("stat".into()))
+    }
+    /// commands waiting for their turn to run
+    fn queue_depth(&self) -> usize {
+        0
+    }
+}
+
+/// directory listings by path, from `fetch_paths`
+pub type Listings = HashMap<String, Result<Vec<FileMeta>, RunnerError>>;
+
+/// `fetch_tree` for runners that can only list one level at a time: a
+/// batch of `fetch_paths` per level
+pub fn fetch_tree_by_level<R: CmdRunner + ?Sized>(
+    runner: &R,
+    path: &str,
+    depth: u32,
+    max_entries: usize,
+) -> Listings {
+    let mut tree = Listings::new();
+    let mut level = vec![tree_key(path)];
+    let mut entries = 0;
+
+    for _ in 0..depth {
+        if level.is_empty() || entries >= max_entries {
+            break;
+        }
+
+        let paths = level.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+        let mut next = vec![];
+        for (dir, listing) in runner.fetch_paths(&paths) {
+            if let Ok(files) = &listing {
+                entries += files.len();
+                next.extend(
+                    files
+                        .iter()
+                        .filter(|f| is_real_dir(f))
+                        .map(|f| join_path(&dir, &f.name)),
+                );
+            }
+            tree.insert(dir, listing);
+        }
+        level = next;
+    }
+
+    tree
+}
+
+/// `path` as keyed in a tree: without trailing slash, except for `/`
+fn tree_key(path: &str) -> String {
+    match path.trim_end_matches('/') {
+        "" => "/".into(),
+        path => path.into(),
+    }
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+/// whether `path` is `dir` or somewhere under it
+fn is_within(path: &str, dir: &str) -> bool {
+    path == dir || path.starts_with(&format!("{}/", dir.trim_end_matches('/')))
+}
+
+/// directories that aren't symlinks, so walking a tree can't loop
+fn is_real_dir(file: &FileMeta) -> bool {
+    file.kind.is_dir()
+}
+
+/// how many levels `dir` is below `root`, both tree keys
+fn depth_below(root: &str, dir: &str) -> usize {
+    dir.strip_prefix(root.trim_end_matches('/'))
+        .unwrap_or(dir)
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .count()
+}
+
+/// whether an error message names directory `dir`, eg.
+/// `find: '/a/b': Permission denied` or `ls: /a/b: Permission denied`
+fn mentioned(stderr: &str, dir: &str) -> bool {
+    ["'", "’", ":", "/'", "/’", "/:"]
+        .iter()
+        .any(|end| stderr.contains(&format!("{}{}", dir, end)))
+}
+
+/// keeps the shallowest directories of a tree under `root`, for as long
+/// as they add up to at most `max_entries` entries. `root` is always kept
+fn select_tree(root: &str, mut dirs: Vec<(String, Vec<FileMeta>)>, max_entries: usize) -> Listings {
+    dirs.sort_by_key(|(dir, _)| depth_below(root, dir));
+
+    let mut tree = Listings::new();
+    let mut entries = 0;
+    for (dir, files) in dirs {
+        entries += files.len();
+        if entries > max_entries && !tree.is_empty() {
+            break;
+        }
+        tree.insert(dir, Ok(files));
+    }
+    tree
+}
+
+/// how long each kind of remote operation may take before it's abandoned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// establishing the ssh connection
+    pub connect: Duration,
+    /// listing a directory
+    pub list: Duration,
+    /// reading (part of) a file
+    pub read: Duration,
 }
 
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            list: Duration::from_secs(30),
+            read: Duration::from_secs(60),
+        }
+    }
+}
+
+/// how transient ssh failures (the connection dropping, refused...) are retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// attempts after the first one
+    pub retries: u32,
+    /// wait before the first retry, doubled for each one after
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Retry {
+    /// wait before retry `attempt` (counting from 0). it's jittered between
+    /// half and all of the exponential backoff so clients that failed
+    /// together don't all come back at once
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let full = self
+            .backoff
+            .checked_mul(1 << attempt.min(16))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        let half = full / 2;
+
+        half + Duration::from_millis(rand::thread_rng().gen_range(0..=half.as_millis() as u64))
+    }
+}
+
+/// A CmdRunner running shell commands (`find`, `ls`, `dd`...) on the other
+/// side of a transport, ssh unless told otherwise
 #[derive(Debug, Clone)]
 pub struct SshCmd {
-    user: String,
-    target: String,
-    options: String,
+    transport: Arc<dyn Transport>,
+    /// directory mounted as `/`, without a trailing slash
+    root: String,
+    /// which listing command the remote understands, one of `LIST_*`
+    list_style: Arc<AtomicU8>,
+    timeouts: Timeouts,
+    retry: Retry,
+    compression: Compression,
+    /// the codec file contents are compressed with, once the remote was
+    /// asked which it has
+    codec: Arc<Mutex<Option<Option<Codec>>>>,
+    /// whether file contents are checked against a remote checksum
+    verify: bool,
+    checksum: Arc<Mutex<Option<Option<Checksum>>>>,
+    /// whether the remote `stat` prints what `parse_stat` reads, one of
+    /// `STAT_*`
+    stat_style: Arc<AtomicU8>,
+    /// seconds east of UTC of the time `ls` prints dates in. 0 unless the
+    /// remote ignores the `TZ` of `REMOTE_ENV`
+    utc_offset: Arc<AtomicI64>,
+    /// how the remote `ls` prints dates in full, one of `LS_TIME_*`
+    ls_time: Arc<AtomicU8>,
+    /// the dialect of the remote `ls`, as 1 + its index in `DIALECTS`, or
+    /// `DIALECT_UNKNOWN`
+    dialect: Arc<AtomicU8>,
 }
 
+/// not tried yet
+const LIST_UNKNOWN: u8 = 0;
+/// GNU find, with `-printf`
+const LIST_FIND: u8 = 1;
+/// plain `ls -l`
+const LIST_LS: u8 = 2;
+
+/// not tried yet
+const STAT_UNKNOWN: u8 = 0;
+/// GNU (or busybox) `stat`
+const STAT_GNU: u8 = 1;
+/// some other `stat`, or none at all
+const STAT_NONE: u8 = 2;
+
+/// plain `ls -l`, to the minute and without the year for recent dates
+const LS_TIME_PLAIN: u8 = 0;
+/// GNU and BusyBox `--full-time`
+const LS_TIME_FULL: u8 = 1;
+/// macOS and BSD `-T`
+const LS_TIME_SECONDS: u8 = 2;
+
+/// not probed, or the probe couldn't tell: each listing tells
+const DIALECT_UNKNOWN: u8 = 0;
+
+/// remote command printing the remote's UTC offset, what its `ls` is (for
+/// `Dialect::from_probe`), the option its `ls` takes to print dates in
+/// full (if any), then `/` listed with it
+const LIST_DETECT_CMD: &str = "date +%z; \
+    case $(ls --version 2>&1) in *GNU*) echo GNU;; *BusyBox*) echo BusyBox;; *) uname -s;; esac; \
+    o=; if ls -ld --full-time / >/dev/null 2>&1; then o=--full-time; \
+    elif ls -ldT / >/dev/null 2>&1; then o=-T; fi; \
+    echo \"$o\"; ls -ld $o /";
+
+/// what every remote command runs with: the C locale, so that dates,
+/// sizes and error messages come out as they're parsed, and UTC, so one
+/// offset holds for dates on either side of a daylight saving change
+const REMOTE_ENV: &str = "LC_ALL=C TZ=UTC0";
+
+/// most directories listed by a single remote command
+const MAX_BATCH: usize = 64;
+
 impl CmdRunner for SshCmd {
-    fn fetch_path(&self, path: &str) -> Option<Vec<FileMeta>> {
-        let path = if path.ends_with("/") {
-            path.into()
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        let path = &self.remote_path(path);
+
+        if self.list_style.load(Ordering::Relaxed) != LIST_LS {
+            let output = self.get_output(&find_cmd(path), self.timeouts.list)?;
+
+            match parse_find_output(&output) {
+                // a find without -printf (BSD, busybox) complains about its arguments
+                Err(RunnerError::Remote(_)) | Err(RunnerError::CommandMissing(_))
+                    if self.list_style.load(Ordering::Relaxed) == LIST_UNKNOWN =>
+                {
+                    self.list_style.store(LIST_LS, Ordering::Relaxed);
+                }
+                result => {
+                    if result.is_ok() {
+                        self.list_style.store(LIST_FIND, Ordering::Relaxed);
+                    }
+                    return result;
+                }
+            }
+        }
+
+        let output = self.get_output(&list_cmd(path, self.ls_time_option()), self.timeouts.list)?;
+
+        parse_list_output(&output, &self.list_format())
+    }
+
+    fn fetch_tree(&self, path: &str, depth: u32, max_entries: usize) -> Listings {
+        let root = tree_key(path);
+        let remote = tree_key(&self.remote_path(&root));
+
+        let cmd = match self.list_style.load(Ordering::Relaxed) {
+            LIST_FIND => find_tree_cmd(&remote, depth, max_entries),
+            // each directory takes a header, a total and a blank line too
+            LIST_LS => list_tree_cmd(&remote, self.ls_time_option(), max_entries * 2),
+            _ => return fetch_tree_by_level(self, path, depth, max_entries),
+        };
+
+        let output = match self.get_output(&cmd, self.timeouts.list) {
+            Ok(output) => output,
+            Err(e) => return vec![(root, Err(e))].into_iter().collect(),
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        let mut dirs = if self.list_style.load(Ordering::Relaxed) == LIST_FIND {
+            if !output.status.success() && output.stdout.is_empty() {
+                let e = RunnerError::from_output(&output);
+                return vec![(root, Err(e))].into_iter().collect();
+            }
+
+            let entries = parse_find_tree(&output.stdout);
+            // past the cap, `head` cut the listing: find goes depth first,
+            // so the last entry's directory and those above it may have
+            // more entries, and the last entry's own weren't reached
+            let cut = match entries.last() {
+                Some((dir, file)) if entries.len() > max_entries => {
+                    Some(join_path(&tree_key(dir), &file.name))
+                }
+                _ => None,
+            };
+
+            // directories down to the one before last were listed in full,
+            // even when empty
+            let mut dirs = HashMap::new();
+            dirs.insert(remote.clone(), vec![]);
+            for (dir, file) in entries {
+                let dir = tree_key(&dir);
+                let path = join_path(&dir, &file.name);
+                if is_real_dir(&file) && depth_below(&remote, &path) < depth as usize {
+                    dirs.entry(path).or_insert_with(Vec::new);
+                }
+                dirs.entry(dir).or_insert_with(Vec::new).push(file);
+            }
+            if let Some(cut) = cut {
+                dirs.retain(|dir, _| !is_within(&cut, dir));
+            }
+            dirs.into_iter().collect::<Vec<_>>()
         } else {
-            format!("{}/", path)
+            let lines = output.stdout.iter().filter(|&&b| b == b'\n').count();
+            let format = self.list_format();
+            let mut sections =
+                parse_recursive_list(&String::from_utf8_lossy(&output.stdout), &format)
+                    .into_iter()
+                    .map(|(dir, files)| match dir.as_str() {
+                        "" => (remote.clone(), files),
+                        _ => (tree_key(&dir), files),
+                    })
+                    .collect::<Vec<_>>();
+            // the last directory was cut short by `head`
+            if lines >= max_entries * 2 {
+                sections.pop();
+            }
+            sections
+                .into_iter()
+                .filter(|(dir, _)| depth_below(&remote, dir) < depth as usize)
+                .collect()
         };
 
-        let cmd = format!("ls -l {}", path);
+        // directories that couldn't be read show up empty
+        dirs.retain(|(dir, _)| !mentioned(&stderr, dir));
+
+        let dirs = dirs
+            .into_iter()
+            .filter_map(|(dir, files)| Some((self.mount_path(&dir)?, files)))
+            .collect();
+
+        let mut tree = select_tree(&root, dirs, max_entries);
+        // eg. it couldn't be read, or has more entries than the cap
+        if !tree.contains_key(&root) {
+            let listing = self.fetch_path(&root);
+            tree.insert(root, listing);
+        }
+        tree
+    }
+
+    fn fetch_paths(&self, paths: &[&str]) -> Listings {
+        let mut listings = Listings::new();
+        let mut paths = paths;
+
+        // until a listing worked it isn't known which command to batch
+        while self.list_style.load(Ordering::Relaxed) == LIST_UNKNOWN {
+            match paths.split_first() {
+                Some((first, rest)) => {
+                    listings.insert(first.to_string(), self.fetch_path(first));
+                    paths = rest;
+                }
+                None => return listings,
+            }
+        }
+
+        let ls = self.list_style.load(Ordering::Relaxed) == LIST_LS;
+        let format = self.list_format();
+        for chunk in paths.chunks(MAX_BATCH) {
+            let marker = format!("sshfuse-{:016x}", rand::random::<u64>());
+            let cmds = chunk.iter().map(|p| {
+                let path = self.remote_path(p);
+                if ls {
+                    list_cmd(&path, self.ls_time_option())
+                } else {
+                    find_cmd(&path)
+                }
+            });
 
-        let output = self.get_output(&cmd).expect("output");
+            let mut sections = match self.get_output(&batch_cmd(&marker, cmds), self.timeouts.list)
+            {
+                Ok(output) => split_batch_output(&marker, &output.stdout),
+                Err(e) => {
+                    for path in chunk {
+                        listings.insert(path.to_string(), Err(e.clone()));
+                    }
+                    continue;
+                }
+            };
 
-        if output.stderr.len() > 0 {
-            println!("Error: {}", str::from_utf8(&output.stderr).unwrap());
+            for (i, path) in chunk.iter().enumerate() {
+                let result = match sections.remove(&i) {
+                    Some(output) if ls => parse_list_output(&output, &format),
+                    Some(output) => parse_find_output(&output),
+                    None => Err(RunnerError::Parse(format!("{} missing from batch", path))),
+                };
+                listings.insert(path.to_string(), result);
+            }
         }
-        let str = &output.stdout;
-        let stdout_utf8 = str::from_utf8(&str).unwrap();
-        println!("Out: {}", stdout_utf8);
 
-        let dir = parse_long_list(stdout_utf8);
+        listings
+    }
+
+    fn fetch_meta(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        if self.stat_style.load(Ordering::Relaxed) != STAT_NONE {
+            let output = self.get_output(&stat_cmd(&self.remote_path(path)), self.timeouts.list)?;
+
+            if !output.status.success() {
+                match RunnerError::from_output(&output) {
+                    RunnerError::CommandMissing(_) => {
+                        self.stat_style.store(STAT_NONE, Ordering::Relaxed)
+                    }
+                    e => return Err(e),
+                }
+            } else if let Some(meta) = parse_stat(&String::from_utf8_lossy(&output.stdout)) {
+                self.stat_style.store(STAT_GNU, Ordering::Relaxed);
+                return Ok(meta);
+            } else {
+                // eg. BSD's, all on one line
+                self.stat_style.store(STAT_NONE, Ordering::Relaxed);
+            }
+        }
 
-        Some(dir)
+        Err(RunnerError::CommandMissing("stat".into()))
     }
 
-    fn fetch_file(&self, path: &str) -> Output {
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
         // reads the file and poke it into a open file cache
-        let cmd = format!("cat {}", path);
+        let path = &self.remote_path(path);
 
-        let output = self.get_output(&cmd).expect("output");
+        // the size isn't known here, the remote checks it
+        let min_size = self.compression.min_size;
+        self.fetch_contents(&cat_cmd(path), path, self.codec(), min_size)
+    }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        let path = &self.remote_path(path);
 
-        output
+        // compressed by the size of the file, checked on the remote, as
+        // ranges are all a block or more
+        let min_size = self.compression.min_size;
+        self.fetch_contents(&range_cmd(path, offset, len), path, self.codec(), min_size)
     }
 }
 
-impl SshCmd {
-    pub fn new(user: &str, target: &str, options: &str) -> Self {
-        let user = user.into();
-        let target = target.into();
-        let options = options.into();
+/// turns the output of `list_cmd` into entries.
+/// `ls` exits non-zero when some entries couldn't be stat-ed, so a
+/// failure only counts when nothing could be listed at all
+fn parse_list_output(output: &Output, format: &ListFormat) -> Result<Vec<FileMeta>, RunnerError> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (dir, unreadable) = read_long_list(&stdout, format);
+
+    if !output.status.success() && dir.is_empty() {
+        return Err(RunnerError::from_output(&output));
+    }
+
+    if !output.stderr.is_empty() {
+        println!("Error: {}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    // anything besides the `total` line should have given us entries
+    if let Some(line) = unreadable.first() {
+        if dir.is_empty() {
+            return Err(RunnerError::Parse(line.to_string()));
+        }
+        println!(
+            "Skipping {} unreadable lines of ls output, eg. {:?}",
+            unreadable.len(),
+            line
+        );
+    }
+
+    Ok(dir)
+}
+
+/// turns the output of `find_cmd` into entries. like `ls`, find keeps
+/// going past entries it can't stat
+fn parse_find_output(output: &Output) -> Result<Vec<FileMeta>, RunnerError> {
+    let dir = parse_find_printf(&output.stdout);
+
+    if !output.status.success() && dir.is_empty() {
+        return Err(RunnerError::from_output(&output));
+    }
+
+    if !output.stderr.is_empty() {
+        println!("Error: {}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    if !output.stdout.is_empty() && dir.is_empty() {
+        return Err(RunnerError::Parse(
+            String::from_utf8_lossy(&output.stdout).into(),
+        ));
+    }
+
+    Ok(dir)
+}
 
+/// remote command listing directory `path` with NUL separated fields,
+/// needs GNU find
+fn find_cmd(path: &str) -> String {
+    let path = if path.ends_with('/') {
+        path.into()
+    } else {
+        format!("{}/", path)
+    };
+
+    format!(
+        "find {} -mindepth 1 -maxdepth 1 -printf {}",
+        shell::quote(&path),
+        shell::quote(FIND_PRINTF)
+    )
+}
+
+/// remote command listing the contents of directory `path`, with
+/// `time_option` from `ls_time_option`.
+/// the trailing slash makes `ls` list a symlinked directory's contents
+fn list_cmd(path: &str, time_option: &str) -> String {
+    let path = if path.ends_with('/') {
+        path.into()
+    } else {
+        format!("{}/", path)
+    };
+
+    format!("ls -l{} -- {}", time_option, shell::quote(&path))
+}
+
+/// remote command listing `path` and the directories under it, `depth`
+/// levels deep, each entry preceded by its directory. stops after one
+/// entry more than `max_entries`
+fn find_tree_cmd(path: &str, depth: u32, max_entries: usize) -> String {
+    format!(
+        "find {} -mindepth 1 -maxdepth {} -printf {} | head -z -n {}",
+        shell::quote(&format!("{}/", path.trim_end_matches('/'))),
+        depth,
+        shell::quote(&format!(r"%h\0{}", FIND_PRINTF)),
+        // one entry past the cap tells the listing was cut
+        (max_entries + 1) * (FIND_FIELDS + 1)
+    )
+}
+
+/// remote command listing `path` recursively with `ls`, which can't be
+/// told how deep to go, so it's cut after `max_lines` lines
+fn list_tree_cmd(path: &str, time_option: &str, max_lines: usize) -> String {
+    format!(
+        "ls -lR{} -- {} | head -n {}",
+        time_option,
+        shell::quote(&format!("{}/", path.trim_end_matches('/'))),
+        max_lines
+    )
+}
+
+/// remote command running each of `cmds`, their output separated by
+/// lines starting with `marker`: `<marker> <n>` before the output of the
+/// nth command, `<marker> <n> <status> <stderr>` after
+fn batch_cmd(marker: &str, cmds: impl Iterator<Item = String>) -> String {
+    let mut batch = format!("m={}", marker);
+    for (i, cmd) in cmds.enumerate() {
+        // stderr goes to $e, stdout through fd 3 to the real stdout
+        batch += &format!(
+            "; printf '%s {i}\\n' \"$m\"; {{ e=$({cmd} 2>&1 >&3); s=$?; }} 3>&1; printf '%s {i} %d %s\\n' \"$m\" \"$s\" \"$e\"",
+            i = i,
+            cmd = cmd
+        );
+    }
+    batch
+}
+
+/// splits the output of `batch_cmd` back into the output of each command,
+/// by its index. a command whose header or trailer got lost is left out
+fn split_batch_output(marker: &str, stdout: &[u8]) -> HashMap<usize, Output> {
+    let marker = format!("{} ", marker).into_bytes();
+
+    let mut pieces = vec![];
+    let mut rest = stdout;
+    while let Some(i) = rest.windows(marker.len()).position(|w| w == &marker[..]) {
+        pieces.push(&rest[..i]);
+        rest = &rest[i + marker.len()..];
+    }
+    pieces.push(rest);
+
+    // before the first marker there's nothing, then each header, with the
+    // output following it, should be followed by its trailer
+    let mut sections = HashMap::new();
+    let mut open: Option<(usize, &[u8])> = None;
+    for piece in &pieces[1..] {
+        let newline = piece
+            .iter()
+            .position(|&b| b == b'\n')
+            .unwrap_or(piece.len());
+        let line = String::from_utf8_lossy(&piece[..newline]);
+        let mut fields = line.splitn(3, ' ');
+        let index = match fields.next().and_then(|i| i.parse::<usize>().ok()) {
+            Some(index) => index,
+            None => continue,
+        };
+
+        match (fields.next(), open.take()) {
+            (None, _) => open = Some((index, piece.get(newline + 1..).unwrap_or_default())),
+            (Some(status), Some((header, body))) if header == index => {
+                let status = match status.parse::<i32>() {
+                    Ok(status) => status,
+                    Err(_) => continue,
+                };
+                sections.insert(
+                    index,
+                    Output {
+                        status: ExitStatus::from_raw(status << 8),
+                        stdout: body.to_vec(),
+                        stderr: fields.next().unwrap_or_default().as_bytes().to_vec(),
+                    },
+                );
+            }
+            _ => {}
+        }
+    }
+
+    sections
+}
+
+/// `cmd` run by `sh` with `REMOTE_ENV`. `env` is a program rather than
+/// shell syntax, so this works whatever the login shell is (csh, fish...)
+fn with_remote_env(cmd: &str) -> String {
+    format!("env {} sh -c {}", REMOTE_ENV, shell::quote(cmd))
+}
+
+/// remote command describing `path` itself, not following links
+fn stat_cmd(path: &str) -> String {
+    format!("stat -- {}", shell::quote(path))
+}
+
+/// remote command printing the contents of file `path`
+fn cat_cmd(path: &str) -> String {
+    format!("cat -- {}", shell::quote(path))
+}
+
+/// remote command printing `len` bytes of `path` from `offset`. dd is
+/// POSIX; it's given the largest block size that both numbers are a
+/// multiple of so aligned reads don't go one byte at a time
+fn range_cmd(path: &str, offset: u64, len: u64) -> String {
+    let mut bs = 1 << 20;
+    while bs > 1 && (offset % bs != 0 || len % bs != 0) {
+        bs >>= 1;
+    }
+
+    format!(
+        "dd if={} bs={} skip={} count={}",
+        shell::quote(path),
+        bs,
+        offset / bs,
+        len / bs
+    )
+}
+
+impl SshCmd {
+    pub fn new(transport: impl Transport + 'static) -> Self {
         Self {
-            user,
-            target,
-            options,
+            transport: Arc::new(transport),
+            root: String::new(),
+            list_style: Arc::new(AtomicU8::new(LIST_UNKNOWN)),
+            timeouts: Timeouts::default(),
+            retry: Retry::default(),
+            compression: Compression::default(),
+            codec: Arc::new(Mutex::new(None)),
+            verify: false,
+            checksum: Arc::new(Mutex::new(None)),
+            stat_style: Arc::new(AtomicU8::new(STAT_UNKNOWN)),
+            utc_offset: Arc::new(AtomicI64::new(0)),
+            ls_time: Arc::new(AtomicU8::new(LS_TIME_PLAIN)),
+            dialect: Arc::new(AtomicU8::new(DIALECT_UNKNOWN)),
+        }
+    }
+
+    /// mounts directory `root` of the other side instead of `/`
+    pub fn with_root(mut self, root: &str) -> Self {
+        self.root = root.trim_end_matches('/').into();
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// what the mount is named after, eg. `user@host` or `docker://web/srv`
+    pub fn name(&self) -> String {
+        format!("{}{}", self.transport.name(), self.root)
+    }
+
+    /// where mount path `path` (always absolute) is on the other side
+    pub fn remote_path(&self, path: &str) -> String {
+        format!("{}{}", self.root, path)
+    }
+
+    /// the mount path of `remote`, a path on the other side
+    fn mount_path(&self, remote: &str) -> Option<String> {
+        let path = remote.strip_prefix(&self.root)?;
+        if !path.is_empty() && !path.starts_with('/') {
+            return None;
+        }
+        Some(tree_key(path))
+    }
+
+    /// a process running `cmd` remotely, for callers that want to talk to
+    /// it over stdin/stdout
+    pub fn remote_command(&self, cmd: &str) -> Command {
+        self.transport.command(&with_remote_env(cmd))
+    }
+
+    /// runs `cmd` remotely, killing it if it takes longer than `timeout`.
+    /// the transport failing (rather than `cmd`) is an error whatever was
+    /// printed, and is retried when it looks transient. the retries share
+    /// `timeout`, and stop when the command is cancelled
+    pub fn get_output(&self, cmd: &str, timeout: Duration) -> Result<Output, RunnerError> {
+        self.get_output_until(cmd, &Deadline::after(timeout))
+    }
+
+    /// `get_output` within a deadline shared with other commands
+    fn get_output_until(&self, cmd: &str, deadline: &Deadline) -> Result<Output, RunnerError> {
+        let mut attempt = 0;
+        loop {
+            let output = process::output_until(self.remote_command(cmd), deadline)?;
+
+            let e = match output.status.code() {
+                Some(255) | None => RunnerError::from_output(&output),
+                _ => return Ok(output),
+            };
+
+            if !e.is_transient() || attempt >= self.retry.retries {
+                return Err(e);
+            }
+
+            println!("{}, retrying", e);
+            self.transport.reset();
+            deadline.sleep(self.retry.delay(attempt))?;
+            attempt += 1;
+        }
+    }
+
+    /// asks the remote once which of the tools we can use it has, with
+    /// `cmd`. later calls wait for and reuse the answer
+    fn detect<T: Copy + std::fmt::Debug>(
+        &self,
+        detected: &Mutex<Option<Option<T>>>,
+        cmd: &str,
+        what: &str,
+        pick: impl FnOnce(&str) -> Option<T>,
+    ) -> Option<T> {
+        let mut detected = detected.lock().unwrap();
+        if detected.is_none() {
+            let found = match self.get_output(cmd, self.timeouts.list) {
+                Ok(output) => pick(&String::from_utf8_lossy(&output.stdout)),
+                // asked again next time
+                Err(e) => {
+                    println!("Can't tell which {} the remote has: {}", what, e);
+                    return None;
+                }
+            };
+
+            match found {
+                Some(found) => println!("Using {:?} as {}", found, what),
+                None => println!("No {} on both ends", what),
+            }
+            *detected = Some(found);
+        }
+
+        detected.unwrap()
+    }
+
+    /// asks the remote for its timezone, which `ls` it has and how it
+    /// prints dates in full, for the listings that follow, and warns when
+    /// what its `ls` prints still can't be read. until then, or when it
+    /// can't tell, dates are read as UTC from plain `ls -l`, in the dialect
+    /// each listing reads best in
+    pub fn detect_list_format(&self) {
+        let output = match self.get_output(LIST_DETECT_CMD, self.timeouts.list) {
+            Ok(output) => output,
+            Err(e) => {
+                println!("Can't tell how the remote lists files: {}", e);
+                return;
+            }
+        };
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines();
+        let zone = lines.next().unwrap_or("").trim();
+        match parse_utc_offset(zone) {
+            Some(offset) => self.utc_offset.store(offset, Ordering::Relaxed),
+            None => println!(
+                "Can't tell the remote's timezone from {:?}, using UTC",
+                zone
+            ),
+        }
+
+        let probe = lines.next().unwrap_or("").trim();
+        match Dialect::from_probe(probe) {
+            Some(dialect) => {
+                let i = DIALECTS.iter().position(|d| *d == dialect).unwrap();
+                self.dialect.store(i as u8 + 1, Ordering::Relaxed);
+                println!("Reading the remote's ls output as {:?}", dialect);
+            }
+            None => println!(
+                "Can't tell which ls the remote has from {:?}, telling from each listing",
+                probe
+            ),
+        }
+
+        let style = match lines.next() {
+            Some("--full-time") => LS_TIME_FULL,
+            Some("-T") => LS_TIME_SECONDS,
+            _ => LS_TIME_PLAIN,
+        };
+        self.ls_time.store(style, Ordering::Relaxed);
+        println!(
+            "Remote timezone {}, listing with ls -l{}",
+            zone,
+            self.ls_time_option()
+        );
+
+        // only matters without GNU find, but it's cheap to tell now
+        let ls = lines.collect::<Vec<_>>().join("\n");
+        let unreadable = unreadable_lines(&ls, &self.list_format());
+        if ls.trim().is_empty() || !unreadable.is_empty() {
+            println!(
+                "Warning: can't read the remote's ls output {:?}, listings without find may miss entries",
+                unreadable.first().copied().unwrap_or(&ls)
+            );
+        }
+    }
+
+    /// what dates in listings made now are relative to
+    fn list_format(&self) -> ListFormat {
+        let format = ListFormat::with_offset(self.utc_offset.load(Ordering::Relaxed));
+        match self.dialect.load(Ordering::Relaxed) {
+            DIALECT_UNKNOWN => format,
+            i => format.with_dialect(DIALECTS[i as usize - 1]),
+        }
+    }
+
+    /// added to `ls -l` for dates with the seconds and the year
+    fn ls_time_option(&self) -> &'static str {
+        match self.ls_time.load(Ordering::Relaxed) {
+            LS_TIME_FULL => " --full-time",
+            LS_TIME_SECONDS => " -T",
+            _ => "",
+        }
+    }
+
+    /// the codec to fetch file contents with, if any
+    fn codec(&self) -> Option<Codec> {
+        if self.compression.mode == CompressMode::Off {
+            return None;
+        }
+
+        let compression = self.compression;
+        self.detect(&self.codec, compress::DETECT_CMD, "compressor", |out| {
+            compression.pick(out)
+        })
+    }
+
+    /// the program file contents are checksummed with, when verifying
+    fn checksum(&self) -> Option<Checksum> {
+        if !self.verify {
+            return None;
+        }
+
+        self.detect(
+            &self.checksum,
+            checksum::DETECT_CMD,
+            "checksum program",
+            Checksum::pick,
+        )
+    }
+
+    /// runs `read_cmd`, which prints the contents of `path`, through
+    /// `codec` when there is one and the file has at least `min_size`
+    /// bytes. contents that don't decompress, or don't match the remote
+    /// checksum when verifying, are fetched again, up to the number of
+    /// retries
+    fn fetch_contents(
+        &self,
+        read_cmd: &str,
+        path: &str,
+        codec: Option<Codec>,
+        min_size: u64,
+    ) -> Result<Vec<u8>, RunnerError> {
+        let mut cmd = match codec {
+            Some(codec) if min_size > 0 => {
+                codec.compressed_cmd_from(read_cmd, &shell::quote(path), min_size)
+            }
+            Some(codec) => codec.compressed_cmd(read_cmd, &shell::quote(path)),
+            None => read_cmd.to_string(),
+        };
+        let checksum = self.checksum();
+        if let Some(checksum) = checksum {
+            cmd = checksum.verified_cmd(&cmd, read_cmd);
+        }
+
+        // the read timeout covers every attempt
+        let deadline = Deadline::after(self.timeouts.read);
+        let mut attempt = 0;
+        loop {
+            let output = self.get_output_until(&cmd, &deadline)?;
+
+            if !output.status.success() {
+                return Err(RunnerError::from_output(&output));
+            }
+
+            let e = match unpack_contents(&output.stdout, path, codec, min_size, checksum) {
+                Ok(contents) => return Ok(contents),
+                Err(e) => e,
+            };
+            if attempt >= self.retry.retries {
+                return Err(e);
+            }
+
+            println!("{}, retrying", e);
+            deadline.sleep(self.retry.delay(attempt))?;
+            attempt += 1;
+        }
+    }
+}
+
+/// the contents in what a `fetch_contents` command printed. `Corrupted`
+/// when they didn't make it intact, eg. a truncated stream or the file
+/// changing while it was read
+fn unpack_contents(
+    stdout: &[u8],
+    path: &str,
+    codec: Option<Codec>,
+    min_size: u64,
+    checksum: Option<Checksum>,
+) -> Result<Vec<u8>, RunnerError> {
+    let (received, expected) = match checksum {
+        Some(checksum) => match checksum.split_output(stdout) {
+            Some((received, digest)) => (received, Some(digest)),
+            None => {
+                return Err(RunnerError::Corrupted(format!(
+                    "no checksum after the contents of {}",
+                    path
+                )))
+            }
+        },
+        None => (stdout, None),
+    };
+
+    let contents = match codec {
+        Some(codec) if min_size > 0 => codec.decompress_marked(received),
+        Some(codec) => codec.decompress(received),
+        None => Ok(received.to_vec()),
+    }
+    .map_err(|e| RunnerError::Corrupted(format!("{}: {}", path, e)))?;
+    STATS.record(contents.len(), stdout.len());
+
+    let digest = checksum.map(|c| c.digest(&contents));
+    if digest != expected {
+        return Err(RunnerError::Corrupted(format!(
+            "checksum mismatch for {}",
+            path
+        )));
+    }
+
+    Ok(contents)
+}
+
+#[test]
+fn test_cmd_quoting() {
+    assert_eq!(list_cmd("/", ""), "ls -l -- /");
+    assert_eq!(list_cmd("/usr", ""), "ls -l -- /usr/");
+    assert_eq!(list_cmd("/tmp/a b", ""), "ls -l -- '/tmp/a b/'");
+    assert_eq!(list_cmd("/", " --full-time"), "ls -l --full-time -- /");
+    assert_eq!(
+        list_tree_cmd("/a", " -T", 10),
+        "ls -lR -T -- /a/ | head -n 10"
+    );
+    assert_eq!(cat_cmd("/tmp/a b"), "cat -- '/tmp/a b'");
+    assert_eq!(cat_cmd("/tmp/x;rm -rf ~"), "cat -- '/tmp/x;rm -rf ~'");
+    assert_eq!(
+        range_cmd("/tmp/a b", 3 * 131072, 131072),
+        "dd if='/tmp/a b' bs=131072 skip=3 count=1"
+    );
+    assert_eq!(range_cmd("/f", 10, 5), "dd if=/f bs=1 skip=10 count=5");
+    assert_eq!(
+        with_remote_env("cat -- '/tmp/a b'"),
+        r"env LC_ALL=C TZ=UTC0 sh -c 'cat -- '\''/tmp/a b'\'''"
+    );
+}
+
+#[test]
+fn test_list_output() {
+    let output = |stdout: &str| Output {
+        status: ExitStatus::from_raw(0),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: vec![],
+    };
+    let utc = ListFormat::utc();
+
+    // what can be read is kept
+    let mixed = format!(
+        "{}{}",
+        include_str!("../fixtures/ls/gnu.txt"),
+        include_str!("../fixtures/ls/de_DE.txt")
+    );
+    assert_eq!(parse_list_output(&output(&mixed), &utc).unwrap().len(), 9);
+
+    match parse_list_output(&output(include_str!("../fixtures/ls/ja_JP.txt")), &utc) {
+        Err(RunnerError::Parse(line)) => assert!(line.ends_with("5月  3 23:27 bin")),
+        other => panic!("{:?}", other),
+    }
+
+    assert!(parse_list_output(&output("total 0\n"), &utc)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_find_cmd() {
+    use crate::ls::FileKind;
+    use crate::testing::TempTree;
+    use std::process::Command;
+
+    assert!(find_cmd("/tmp/a b").starts_with("find '/tmp/a b/' -mindepth 1 -maxdepth 1 -printf '"));
+
+    // only meaningful where the local find is GNU's
+    let gnu = Command::new("find")
+        .args(&["/", "-maxdepth", "0", "-printf", "%y"])
+        .output()
+        .map(|o| o.stdout == b"d")
+        .unwrap_or(false);
+    if !gnu {
+        return;
+    }
+
+    let root = TempTree::new("find");
+    std::fs::create_dir_all(root.join("sub dir")).unwrap();
+    std::fs::write(root.join("new\nline"), "contents").unwrap();
+    std::os::unix::fs::symlink("sub dir", root.join("link")).unwrap();
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(find_cmd(root.to_str().unwrap()))
+        .output()
+        .unwrap();
+    let mut dir = parse_find_output(&output).unwrap();
+    dir.sort_by(|a, b| a.name.cmp(&b.name));
+
+    assert_eq!(
+        dir.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+        &["link", "new\nline", "sub dir"]
+    );
+    assert_eq!(dir[0].kind, FileKind::Symlink("sub dir".into()));
+    assert_eq!(dir[1].file_size, 8);
+    assert!(dir[1].inode.is_some());
+    assert!(dir[2].kind.is_dir());
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(find_cmd(root.join("nope").to_str().unwrap()))
+        .output()
+        .unwrap();
+    assert_eq!(
+        parse_find_output(&output).unwrap_err().errno(),
+        libc::ENOENT
+    );
+}
+
+#[test]
+fn test_retry_delay() {
+    let retry = Retry::default();
+
+    for _ in 0..20 {
+        let first = retry.delay(0);
+        assert!(first >= Duration::from_millis(100) && first <= Duration::from_millis(200));
+
+        let third = retry.delay(2);
+        assert!(third >= Duration::from_millis(400) && third <= Duration::from_millis(800));
+
+        assert!(retry.delay(40) <= retry.max_backoff);
+    }
+}
+
+#[test]
+fn test_retry_deadline() {
+    use crate::transport::ShellTransport;
+    use std::time::Instant;
+
+    let runner = SshCmd::new(ShellTransport).with_retry(Retry {
+        retries: 1000,
+        backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(50),
+    });
+
+    // retrying a connection that keeps dropping stops at the timeout
+    let started = Instant::now();
+    assert_eq!(
+        runner
+            .get_output("exit 255", Duration::from_millis(300))
+            .unwrap_err(),
+        RunnerError::Timeout
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // and when whoever asked is gone
+    let mut gone = Command::new("true").spawn().unwrap();
+    let pid = gone.id();
+    gone.wait().unwrap();
+    let result = process::on_behalf_of(pid, || {
+        runner.get_output("exit 255", Duration::from_secs(30))
+    });
+    assert_eq!(result.unwrap_err(), RunnerError::Cancelled);
+}
+
+#[test]
+fn test_shell_transport() {
+    use crate::ls::FileKind;
+    use crate::testing::TempTree;
+    use crate::transport::ShellTransport;
+    use std::fs;
+
+    let root = TempTree::new("sh");
+    fs::create_dir_all(root.join("sub dir")).unwrap();
+    fs::write(root.join("sub dir/it's here"), "contents").unwrap();
+
+    // the whole command path, without a remote
+    let runner = SshCmd::new(ShellTransport).with_root(&format!("{}/", root.display()));
+    assert_eq!(runner.name(), format!("sh://{}", root.display()));
+
+    let dir = runner.fetch_path("/").unwrap();
+    assert_eq!(dir.len(), 1);
+    assert_eq!(dir[0].name, "sub dir");
+    assert!(dir[0].kind.is_dir());
+
+    let dir = runner.fetch_path("/sub dir").unwrap();
+    assert_eq!(dir[0].name, "it's here");
+    assert_eq!(dir[0].file_size, 8);
+
+    assert_eq!(
+        runner.fetch_range("/sub dir/it's here", 2, 4).unwrap(),
+        b"ntents"[..4].to_vec()
+    );
+    assert_eq!(
+        runner.fetch_file("/sub dir/it's here").unwrap(),
+        b"contents".to_vec()
+    );
+    assert_eq!(
+        runner.fetch_path("/nope").unwrap_err().errno(),
+        libc::ENOENT
+    );
+
+    // the remote's locale and timezone don't get through
+    let output = runner
+        .remote_command("echo $LC_ALL $TZ; date +%z")
+        .env("LC_ALL", "de_DE.UTF-8")
+        .env("TZ", "Asia/Tokyo")
+        .output()
+        .unwrap();
+    assert_eq!(output.stdout, b"C UTC0\n+0000\n");
+
+    // with `stat` where it's GNU, left to listing the parent elsewhere
+    match runner.fetch_meta("/sub dir/it's here") {
+        Err(RunnerError::CommandMissing(_)) => {}
+        meta => {
+            let meta = meta.unwrap();
+            assert_eq!(meta.name, "it's here");
+            assert_eq!(meta.file_size, 8);
+            assert_eq!(meta.kind, FileKind::File);
+            assert_eq!(
+                runner.fetch_meta("/sub dir/nope").unwrap_err().errno(),
+                libc::ENOENT
+            );
         }
     }
 
-    fn get_full_cmd(&self, cmd: &str) -> String {
-        format!(
-            "ssh {} {}@{} -- {}",
-            self.options, self.user, self.target, cmd
+    // the same through a compressor and checksummed, when there are
+    // programs for that around
+    let compressed = runner
+        .with_compression(Compression {
+            mode: CompressMode::Auto,
+            min_size: 0,
+        })
+        .with_verify(true);
+    assert_eq!(
+        compressed.fetch_range("/sub dir/it's here", 2, 4).unwrap(),
+        b"nten".to_vec()
+    );
+    assert_eq!(
+        compressed.fetch_file("/sub dir/it's here").unwrap(),
+        b"contents".to_vec()
+    );
+    assert_eq!(
+        compressed.fetch_file("/nope").unwrap_err().errno(),
+        libc::ENOENT
+    );
+}
+
+#[test]
+fn test_unpack_contents() {
+    let md5 = Some(Checksum::Md5Sum);
+    let intact = format!("data{}  -\n", Checksum::Md5Sum.digest(b"data"));
+    assert_eq!(
+        unpack_contents(intact.as_bytes(), "/f", None, 0, md5).unwrap(),
+        b"data"
+    );
+
+    // all of these are fetched again
+    let corrupted = |stdout: &[u8], codec: Option<Codec>, min_size: u64| {
+        matches!(
+            unpack_contents(stdout, "/f", codec, min_size, md5),
+            Err(RunnerError::Corrupted(_))
         )
+    };
+    assert!(corrupted(b"data", None, 0));
+    assert!(corrupted(
+        intact.replace("data", "date").as_bytes(),
+        None,
+        0
+    ));
+    assert!(corrupted(intact.as_bytes(), Some(Codec::Gzip), 1));
+}
+
+#[test]
+fn test_fetch_paths() {
+    use crate::testing::TempTree;
+    use crate::transport::ShellTransport;
+    use std::fs;
+
+    let root = TempTree::new("batch");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::create_dir_all(root.join("b c")).unwrap();
+    fs::write(root.join("a/one"), "1").unwrap();
+    fs::write(root.join("b c/two"), "22").unwrap();
+    fs::write(root.join("b c/three"), "333").unwrap();
+
+    let runner = SshCmd::new(ShellTransport).with_root(root.as_str());
+    let names = |listing: &Result<Vec<FileMeta>, RunnerError>| {
+        let mut names = listing
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|f| f.name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    runner.detect_list_format();
+    let mut modified = vec![];
+    for style in &[LIST_FIND, LIST_LS] {
+        runner.list_style.store(*style, Ordering::Relaxed);
+
+        let listings = runner.fetch_paths(&["/", "/a", "/nope", "/b c"]);
+        modified.push(listings["/a"].as_ref().unwrap()[0].modified);
+        assert_eq!(listings.len(), 4);
+        assert_eq!(names(&listings["/"]), &["a", "b c"]);
+        assert_eq!(names(&listings["/a"]), &["one"]);
+        assert_eq!(names(&listings["/b c"]), &["three", "two"]);
+        assert_eq!(
+            listings["/nope"].as_ref().unwrap_err().errno(),
+            libc::ENOENT
+        );
     }
+    // `ls --full-time` is as precise as find, whatever the timezone, and
+    // the probe knows its ls
+    if runner.ls_time.load(Ordering::Relaxed) == LS_TIME_FULL {
+        assert_eq!(modified[0], modified[1]);
+        assert!(matches!(
+            runner.list_format().dialect,
+            Some(Dialect::Gnu) | Some(Dialect::Busybox)
+        ));
+    }
+
+    let sections = split_batch_output("mk", b"mk 0\nmk 0 0 \nmk 1\nnot a mk\nmk 1 2 oops\n");
+    assert_eq!(sections.len(), 2);
+    assert!(sections[&0].stdout.is_empty());
+    assert_eq!(sections[&1].stdout, b"not a mk\n");
+    assert_eq!(sections[&1].status.code(), Some(2));
+    assert_eq!(sections[&1].stderr, b"oops");
+
+    // output that got cut short isn't taken for another command's
+    let sections = split_batch_output("mk", b"mk 0\nzero\nmk 1\none\nmk 1 0 \n");
+    assert_eq!(sections.len(), 1);
+    assert_eq!(sections[&1].stdout, b"one\n");
+}
+
+#[test]
+fn test_fetch_tree() {
+    use crate::testing::TempTree;
+    use crate::transport::ShellTransport;
+    use std::fs;
+
+    let root = TempTree::new("tree");
+    fs::create_dir_all(root.join("a/b/c")).unwrap();
+    fs::create_dir_all(root.join("empty")).unwrap();
+    fs::write(root.join("a/one"), "1").unwrap();
+    fs::write(root.join("a/b/two"), "22").unwrap();
+    std::os::unix::fs::symlink("a", root.join("loop")).unwrap();
+
+    let runner = SshCmd::new(ShellTransport).with_root(root.as_str());
+    let sorted = |tree: &Listings| {
+        let mut dirs = tree
+            .iter()
+            .map(|(dir, files)| (dir.clone(), files.as_ref().unwrap().len()))
+            .collect::<Vec<_>>();
+        dirs.sort();
+        dirs
+    };
+    let pairs = |dirs: &[(&str, usize)]| {
+        dirs.iter()
+            .map(|(d, n)| (d.to_string(), *n))
+            .collect::<Vec<_>>()
+    };
+
+    for style in &[LIST_UNKNOWN, LIST_FIND, LIST_LS] {
+        runner.list_style.store(*style, Ordering::Relaxed);
+
+        // `c` is 3 levels down, its own listing isn't fetched
+        let tree = runner.fetch_tree("/", 3, 100);
+        assert_eq!(
+            sorted(&tree),
+            pairs(&[("/", 3), ("/a", 2), ("/a/b", 2), ("/empty", 0)]),
+            "style {}",
+            style
+        );
+
+        let tree = runner.fetch_tree("/a/", 5, 100);
+        assert_eq!(
+            sorted(&tree),
+            pairs(&[("/a", 2), ("/a/b", 2), ("/a/b/c", 0)])
+        );
+
+        // the shallowest directories fill the cap
+        let tree = runner.fetch_tree("/", 3, 5);
+        assert!(tree["/"].is_ok());
+        assert!(!tree.contains_key("/a/b"));
+
+        // wherever the cap cuts, directories are listed in full or not at all
+        for max_entries in 1..8 {
+            for (dir, listing) in runner.fetch_tree("/", 3, max_entries) {
+                assert_eq!(
+                    listing.unwrap().len(),
+                    runner.fetch_path(&dir).unwrap().len(),
+                    "{} capped at {}",
+                    dir,
+                    max_entries
+                );
+            }
+        }
+
+        assert_eq!(
+            runner.fetch_tree("/nope", 2, 100)["/nope"]
+                .as_ref()
+                .unwrap_err()
+                .errno(),
+            libc::ENOENT
+        );
+    }
+}
+
+#[test]
+fn test_range_cmd() {
+    use crate::testing::TempTree;
+    use std::process::Command;
 
-    pub fn get_output(&self, cmd: &str) -> Result<Output, std::io::Error> {
-        let raw_cmd = self.get_full_cmd(cmd);
-        let mut parts = raw_cmd.split_whitespace();
-        let cmd = parts
-            .next()
-            .ok_or(std::io::Error::new(ErrorKind::Other, "can't split cmd"))?;
-        let args = parts.collect::<Vec<_>>();
+    let dir = TempTree::new("dd");
+    let path = dir.join("contents");
+    let contents = (0..100_000u32)
+        .flat_map(|i| i.to_le_bytes())
+        .collect::<Vec<_>>();
+    std::fs::write(&path, &contents).unwrap();
+    let path_str = path.to_str().unwrap();
 
-        let process = Command::new(cmd).args(&args).output();
+    for &(offset, len) in &[
+        (0, 4096),
+        (8192, 65536),
+        (3, 17),
+        (399_990, 100),
+        (500_000, 10),
+    ] {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(range_cmd(path_str, offset, len))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
 
-        process
+        let start = (offset as usize).min(contents.len());
+        let end = (offset + len).min(contents.len() as u64) as usize;
+        assert_eq!(output.stdout, &contents[start..end], "{} {}", offset, len);
     }
 }
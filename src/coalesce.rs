@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use crate::cmd::{CmdRunner, Listings};
+use crate::error::RunnerError;
+use crate::ls::FileMeta;
+
+/// A CmdRunner that runs identical fetches only once. When a fetch for the
+/// same path (and range) is already in flight, later callers wait for its
+/// result instead of sending their own remote command, eg. when several
+/// threads `getattr` siblings in a directory that isn't cached yet.
+pub struct Coalesced<R> {
+    runner: R,
+    paths: SingleFlight<Vec<FileMeta>>,
+    files: SingleFlight<Vec<u8>>,
+    ranges: SingleFlight<Vec<u8>>,
+    trees: SingleFlight<Listings>,
+    metas: SingleFlight<FileMeta>,
+}
+
+impl<R: CmdRunner> Coalesced<R> {
+    pub fn new(runner: R) -> Self {
+        Self {
+            runner,
+            paths: Default::default(),
+            files: Default::default(),
+            ranges: Default::default(),
+            trees: Default::default(),
+            metas: Default::default(),
+        }
+    }
+}
+
+impl<R: CmdRunner> CmdRunner for Coalesced<R> {
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        self.paths
+            .run(path.to_string(), || self.runner.fetch_path(path))
+    }
+
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+        self.files
+            .run(path.to_string(), || self.runner.fetch_file(path))
+    }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        self.ranges.run(format!("{}@{}+{}", path, offset, len), || {
+            self.runner.fetch_range(path, offset, len)
+        })
+    }
+
+    // batches are rarely identical, they go through as they are
+    fn fetch_paths(&self, paths: &[&str]) -> Listings {
+        self.runner.fetch_paths(paths)
+    }
+
+    fn fetch_tree(&self, path: &str, depth: u32, max_entries: usize) -> Listings {
+        let key = format!("{}^{}+{}", path, depth, max_entries);
+        self.trees
+            .run(key, || Ok(self.runner.fetch_tree(path, depth, max_entries)))
+            .unwrap_or_else(|e| vec![(path.to_string(), Err(e))].into_iter().collect())
+    }
+
+    fn fetch_meta(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        self.metas
+            .run(path.to_string(), || self.runner.fetch_meta(path))
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.runner.queue_depth()
+    }
+}
+
+type Reply<T> = Result<T, RunnerError>;
+
+/// results of fetches in flight, by key
+struct SingleFlight<T> {
+    flights: Mutex<HashMap<String, Arc<Flight<T>>>>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            flights: Default::default(),
+        }
+    }
+}
+
+struct Flight<T> {
+    reply: Mutex<Option<Reply<T>>>,
+    done: Condvar,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    /// runs `fetch` unless one for `key` is already running, in which case
+    /// its result is shared. a fetch cancelled for whoever ran it is run
+    /// again for the others, by one of them
+    fn run(&self, key: String, fetch: impl FnOnce() -> Reply<T>) -> Reply<T> {
+        loop {
+            let (flight, leader) = {
+                let mut flights = self.flights.lock().unwrap();
+                match flights.get(&key) {
+                    Some(flight) => (flight.clone(), false),
+                    None => {
+                        let flight = Arc::new(Flight {
+                            reply: Mutex::new(None),
+                            done: Condvar::new(),
+                        });
+                        flights.insert(key.clone(), flight.clone());
+                        (flight, true)
+                    }
+                }
+            };
+
+            if !leader {
+                let mut reply = flight.reply.lock().unwrap();
+                while reply.is_none() {
+                    reply = flight.done.wait(reply).unwrap();
+                }
+                match reply.clone().unwrap() {
+                    Err(RunnerError::Cancelled) => continue,
+                    reply => return reply,
+                }
+            }
+
+            // answers the waiters even if fetch panics
+            let landing = Landing {
+                single: self,
+                key,
+                flight: &flight,
+            };
+            let reply = fetch();
+            *flight.reply.lock().unwrap() = Some(reply.clone());
+            drop(landing);
+
+            return reply;
+        }
+    }
+}
+
+/// takes a finished flight off the map and wakes whoever is waiting on it
+struct Landing<'a, T> {
+    single: &'a SingleFlight<T>,
+    key: String,
+    flight: &'a Flight<T>,
+}
+
+impl<T> Drop for Landing<'_, T> {
+    fn drop(&mut self) {
+        self.single.flights.lock().unwrap().remove(&self.key);
+
+        let mut reply = self.flight.reply.lock().unwrap();
+        if reply.is_none() {
+            *reply = Some(Err(RunnerError::Io("fetch panicked".into())));
+        }
+        self.flight.done.notify_all();
+    }
+}
+
+#[test]
+fn test_coalesced() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{thread, time::Duration};
+
+    struct CountingRunner {
+        calls: AtomicUsize,
+    }
+
+    impl CmdRunner for CountingRunner {
+        fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            if path == "/nope" {
+                return Err(RunnerError::NotFound(path.into()));
+            }
+            Ok(vec![FileMeta {
+                name: path.into(),
+                ..Default::default()
+            }])
+        }
+
+        fn fetch_file(&self, _path: &str) -> Result<Vec<u8>, RunnerError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            Ok(b"contents".to_vec())
+        }
+
+        fn fetch_range(&self, path: &str, _offset: u64, _len: u64) -> Result<Vec<u8>, RunnerError> {
+            self.fetch_file(path)
+        }
+    }
+
+    let runner = Arc::new(Coalesced::new(CountingRunner {
+        calls: AtomicUsize::new(0),
+    }));
+
+    let spawn = |path: &'static str| {
+        let runner = runner.clone();
+        thread::spawn(move || runner.fetch_path(path))
+    };
+
+    let threads = (0..8).map(|_| spawn("/usr")).collect::<Vec<_>>();
+    let missing = (0..4).map(|_| spawn("/nope")).collect::<Vec<_>>();
+
+    for t in threads {
+        assert_eq!(t.join().unwrap().unwrap()[0].name, "/usr");
+    }
+    for t in missing {
+        assert_eq!(t.join().unwrap().unwrap_err().errno(), libc::ENOENT);
+    }
+    assert_eq!(runner.runner.calls.load(Ordering::SeqCst), 2);
+
+    // finished fetches aren't remembered
+    runner.fetch_file("/f").unwrap();
+    runner.fetch_file("/f").unwrap();
+    assert_eq!(runner.runner.calls.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn test_coalesced_cancel() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::{thread, time::Duration};
+
+    /// the first fetch is cancelled, eg. its requester was killed
+    struct CancelFirst {
+        calls: AtomicUsize,
+    }
+
+    impl CmdRunner for CancelFirst {
+        fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            if call == 0 {
+                return Err(RunnerError::Cancelled);
+            }
+            Ok(vec![FileMeta {
+                name: path.into(),
+                ..Default::default()
+            }])
+        }
+
+        fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+            Err(RunnerError::NotFound(path.into()))
+        }
+
+        fn fetch_range(&self, path: &str, _offset: u64, _len: u64) -> Result<Vec<u8>, RunnerError> {
+            Err(RunnerError::NotFound(path.into()))
+        }
+    }
+
+    let runner = Arc::new(Coalesced::new(CancelFirst {
+        calls: AtomicUsize::new(0),
+    }));
+
+    let leader = {
+        let runner = runner.clone();
+        thread::spawn(move || runner.fetch_path("/usr"))
+    };
+    thread::sleep(Duration::from_millis(20));
+    let followers = (0..4)
+        .map(|_| {
+            let runner = runner.clone();
+            thread::spawn(move || runner.fetch_path("/usr"))
+        })
+        .collect::<Vec<_>>();
+
+    // only the one who was cancelled sees it, the rest fetch again, once
+    assert_eq!(leader.join().unwrap().unwrap_err(), RunnerError::Cancelled);
+    for t in followers {
+        assert_eq!(t.join().unwrap().unwrap()[0].name, "/usr");
+    }
+    assert_eq!(runner.runner.calls.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,250 @@
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+    str::FromStr,
+    thread,
+};
+
+/// A compressor that can run on the remote and undo its work locally
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    fn program(self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// whether the program is installed here, to decompress with
+    fn available_locally(self) -> bool {
+        Command::new(self.program())
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false)
+    }
+
+    /// wraps remote command `read_cmd`, which prints the contents of
+    /// `quoted_path`, to compress its output. a pipe's status is that of
+    /// its last command, so paths that can't be read are left to
+    /// `read_cmd` alone to fail with a proper error
+    pub fn compressed_cmd(self, read_cmd: &str, quoted_path: &str) -> String {
+        format!(
+            "if [ -r {path} ] && [ ! -d {path} ]; then {read} | {codec} -c; else {read}; fi",
+            path = quoted_path,
+            read = read_cmd,
+            codec = self.program()
+        )
+    }
+
+    /// like `compressed_cmd`, for reads whose length isn't known up front:
+    /// only files of at least `min_size` bytes are compressed. the output
+    /// starts with `z` when it's compressed and `p` when it's not, which
+    /// `decompress_marked` takes off
+    pub fn compressed_cmd_from(self, read_cmd: &str, quoted_path: &str, min_size: u64) -> String {
+        format!(
+            "if [ -r {path} ] && [ ! -d {path} ] && [ -n \"$(find {path} -prune -size +{below}c)\" ]; \
+             then printf z; {read} | {codec} -c; else printf p; {read}; fi",
+            path = quoted_path,
+            below = min_size.saturating_sub(1),
+            read = read_cmd,
+            codec = self.program()
+        )
+    }
+
+    /// the contents printed by a `compressed_cmd_from` command
+    pub fn decompress_marked(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match data.split_first() {
+            Some((b'z', compressed)) => self.decompress(compressed),
+            Some((b'p', plain)) => Ok(plain.to_vec()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "neither compressed nor plain",
+            )),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut child = Command::new(self.program())
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // fed from another thread so neither pipe fills up and blocks
+        let mut stdin = child.stdin.take().unwrap();
+        let data = data.to_vec();
+        let writer = thread::spawn(move || stdin.write_all(&data));
+
+        let output = child.wait_with_output()?;
+        let _ = writer.join();
+
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} -dc: {}",
+                    self.program(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            ));
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+/// `--compress` setting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressMode {
+    Off,
+    /// the best codec both sides have
+    Auto,
+    Only(Codec),
+}
+
+impl FromStr for CompressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" | "none" => Ok(CompressMode::Off),
+            "auto" => Ok(CompressMode::Auto),
+            "gzip" => Ok(CompressMode::Only(Codec::Gzip)),
+            "zstd" => Ok(CompressMode::Only(Codec::Zstd)),
+            _ => Err(format!("expected off, auto, gzip or zstd, got {}", s)),
+        }
+    }
+}
+
+/// Whether and when file contents are fetched compressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    pub mode: CompressMode,
+    /// reads smaller than this aren't worth compressing
+    pub min_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            mode: CompressMode::Off,
+            min_size: 64 * 1024,
+        }
+    }
+}
+
+/// remote command printing which codecs the remote has, one per line
+pub const DETECT_CMD: &str =
+    "for c in zstd gzip; do command -v $c >/dev/null 2>&1 && echo $c; done";
+
+impl Compression {
+    /// the codec to use given the output of `DETECT_CMD`
+    pub fn pick(&self, detected: &str) -> Option<Codec> {
+        let remote = detected
+            .lines()
+            .filter_map(|l| match l.trim() {
+                "zstd" => Some(Codec::Zstd),
+                "gzip" => Some(Codec::Gzip),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let candidates = match self.mode {
+            CompressMode::Off => vec![],
+            CompressMode::Auto => vec![Codec::Zstd, Codec::Gzip],
+            CompressMode::Only(codec) => vec![codec],
+        };
+
+        candidates
+            .into_iter()
+            .find(|c| remote.contains(c) && c.available_locally())
+    }
+}
+
+#[test]
+fn test_compression() {
+    use crate::shell;
+    use crate::testing::TempTree;
+
+    let auto = Compression {
+        mode: CompressMode::Auto,
+        ..Default::default()
+    };
+    let detected = Command::new("sh")
+        .arg("-c")
+        .arg(DETECT_CMD)
+        .output()
+        .unwrap();
+    let codec = match auto.pick(&String::from_utf8_lossy(&detected.stdout)) {
+        Some(codec) => codec,
+        None => {
+            println!("no gzip or zstd, skipping");
+            return;
+        }
+    };
+
+    assert_eq!(Compression::default().pick("zstd\ngzip\n"), None);
+    assert_eq!("gzip".parse(), Ok(CompressMode::Only(Codec::Gzip)));
+    assert!("lz4".parse::<CompressMode>().is_err());
+
+    let dir = TempTree::new("compress");
+    let path = dir.join("log");
+    let contents = "a fairly repetitive log line\n".repeat(10_000);
+    std::fs::write(&path, &contents).unwrap();
+    let quoted = shell::quote(path.to_str().unwrap());
+
+    let run = |cmd: &str| {
+        Command::new("sh")
+            .arg("-c")
+            .arg(codec.compressed_cmd(cmd, &quoted))
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&format!("cat -- {}", quoted));
+    assert!(output.status.success());
+    assert!(output.stdout.len() < contents.len() / 10);
+    assert_eq!(
+        codec.decompress(&output.stdout).unwrap(),
+        contents.as_bytes()
+    );
+
+    // errors come through as if uncompressed
+    std::fs::remove_file(&path).unwrap();
+    let output = run(&format!("cat -- {}", quoted));
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No such file or directory"));
+
+    assert!(codec.decompress(b"not compressed").is_err());
+
+    // with a threshold, only files at least that big are compressed
+    std::fs::write(&path, &contents).unwrap();
+    let run_from = |min_size: u64| {
+        let cmd = format!("cat -- {}", quoted);
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(codec.compressed_cmd_from(&cmd, &quoted, min_size))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+    let len = contents.len() as u64;
+    for (min_size, compressed) in &[(len, true), (len + 1, false), (1, true)] {
+        let output = run_from(*min_size);
+        assert_eq!(output[0] == b'z', *compressed);
+        assert_eq!(
+            codec.decompress_marked(&output).unwrap(),
+            contents.as_bytes()
+        );
+    }
+}
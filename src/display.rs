@@ -1,53 +1,191 @@
-use std::process::Output;
+use std::{
+    process::Output,
+    sync::{Arc, Mutex, Weak},
+};
 
 use crate::spinners;
+use crate::stats::STATS;
 use crate::{
-    cmd::{CmdRunner, SshCmd},
+    cmd::{CmdRunner, Listings, SshCmd},
+    error::RunnerError,
     ls::FileMeta,
 };
 use console::style;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/// spinners being drawn, so they can be hidden while the terminal is
+/// needed for something else
+static ACTIVE_VIEWS: Mutex<Vec<Weak<MultiProgress>>> = Mutex::new(Vec::new());
+
+/// runs `f` with the spinners hidden, eg. to prompt for a password
+/// without it being drawn over
+pub fn suspend_spinners<T>(f: impl FnOnce() -> T) -> T {
+    let views = ACTIVE_VIEWS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+
+    for v in &views {
+        v.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let result = f();
+    for v in &views {
+        v.set_draw_target(ProgressDrawTarget::stderr());
+    }
 
-pub struct RunnerWithSpinner {
-    cmd: SshCmd,
-    views: MultiProgress,
+    result
 }
 
-impl RunnerWithSpinner {
-    pub fn new(user: &str, target: &str, options: &str) -> Self {
-        let views = MultiProgress::new();
+pub struct RunnerWithSpinner<R> {
+    cmd: R,
+    views: Arc<MultiProgress>,
+}
+
+impl<R: CmdRunner> RunnerWithSpinner<R> {
+    pub fn new(cmd: R) -> Self {
+        let views = Arc::new(MultiProgress::new());
         // let trace_bar = get_progress_bar(&views);
 
-        Self {
-            views,
-            cmd: SshCmd::new(&user, &target, &options),
-        }
+        let mut active = ACTIVE_VIEWS.lock().unwrap();
+        active.retain(|v| v.strong_count() > 0);
+        active.push(Arc::downgrade(&views));
+
+        Self { views, cmd }
     }
 }
 
-impl CmdRunner for RunnerWithSpinner {
+impl<R: CmdRunner> CmdRunner for RunnerWithSpinner<R> {
     // use overly generalized view for now
-    fn fetch_path(&self, path: &str) -> Option<Vec<FileMeta>> {
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
         let pb = get_progress_bar(&self.views);
         let cmd_fmt = style(path).dim().bold();
-        pb.set_message(format!("Fetching path {}...", cmd_fmt));
+        pb.set_message(format!(
+            "Fetching path {}...{}",
+            cmd_fmt,
+            queued(self.cmd.queue_depth())
+        ));
         pb.enable_steady_tick(75);
 
         let o = self.cmd.fetch_path(path);
-        pb.finish_with_message(format!("Done: {}", &cmd_fmt));
+        finish(&pb, &cmd_fmt, o.as_ref().err());
         o
     }
 
-    fn fetch_file(&self, path: &str) -> Output {
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
         let pb = get_progress_bar(&self.views);
         let cmd_fmt = style(path).dim().bold();
-        pb.set_message(format!("Fetching file {}...", cmd_fmt));
+        pb.set_message(format!(
+            "Fetching file {}...{}",
+            cmd_fmt,
+            queued(self.cmd.queue_depth())
+        ));
         pb.enable_steady_tick(75);
 
         let o = self.cmd.fetch_file(path);
-        pb.finish_with_message(format!("Done: {}", &cmd_fmt));
+        finish(&pb, &with_stats(&cmd_fmt), o.as_ref().err());
         o
     }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        let pb = get_progress_bar(&self.views);
+        let cmd_fmt = style(format!("{} @{}+{}", path, offset, len)).dim().bold();
+        pb.set_message(format!(
+            "Fetching range {}...{}",
+            cmd_fmt,
+            queued(self.cmd.queue_depth())
+        ));
+        pb.enable_steady_tick(75);
+
+        let o = self.cmd.fetch_range(path, offset, len);
+        finish(&pb, &with_stats(&cmd_fmt), o.as_ref().err());
+        o
+    }
+
+    fn fetch_paths(&self, paths: &[&str]) -> Listings {
+        let pb = get_progress_bar(&self.views);
+        let summary = match paths {
+            [] | [_] => paths.join(""),
+            _ => format!("{} and {} more", paths[0], paths.len() - 1),
+        };
+        let cmd_fmt = style(summary).dim().bold();
+        pb.set_message(format!(
+            "Fetching paths {}...{}",
+            cmd_fmt,
+            queued(self.cmd.queue_depth())
+        ));
+        pb.enable_steady_tick(75);
+
+        let o = self.cmd.fetch_paths(paths);
+        let failed = o.values().filter_map(|r| r.as_ref().err()).next();
+        finish(&pb, &cmd_fmt, failed);
+        o
+    }
+
+    fn fetch_tree(&self, path: &str, depth: u32, max_entries: usize) -> Listings {
+        let pb = get_progress_bar(&self.views);
+        let cmd_fmt = style(format!("{} ({} levels)", path, depth)).dim().bold();
+        pb.set_message(format!(
+            "Fetching tree {}...{}",
+            cmd_fmt,
+            queued(self.cmd.queue_depth())
+        ));
+        pb.enable_steady_tick(75);
+
+        let o = self.cmd.fetch_tree(path, depth, max_entries);
+        let failed = o.values().filter_map(|r| r.as_ref().err()).next();
+        finish(&pb, &cmd_fmt, failed);
+        o
+    }
+
+    fn fetch_meta(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        let pb = get_progress_bar(&self.views);
+        let cmd_fmt = style(path).dim().bold();
+        pb.set_message(format!(
+            "Fetching metadata {}...{}",
+            cmd_fmt,
+            queued(self.cmd.queue_depth())
+        ));
+        pb.enable_steady_tick(75);
+
+        let o = self.cmd.fetch_meta(path);
+        finish(&pb, &cmd_fmt, o.as_ref().err());
+        o
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.cmd.queue_depth()
+    }
+}
+
+/// note about commands waiting ahead of this one
+fn queued(depth: usize) -> String {
+    if depth == 0 {
+        String::new()
+    } else {
+        format!(" {}", style(format!("({} queued)", depth)).yellow())
+    }
+}
+
+/// `cmd_fmt` followed by the transfer totals so far
+fn with_stats(cmd_fmt: &impl std::fmt::Display) -> String {
+    format!(
+        "{} {}",
+        cmd_fmt,
+        style(format!("[{}]", STATS.summary())).dim()
+    )
+}
+
+fn finish(pb: &ProgressBar, cmd_fmt: &impl std::fmt::Display, err: Option<&RunnerError>) {
+    match err {
+        Some(e) => pb.finish_with_message(format!(
+            "Failed: {} {}",
+            cmd_fmt,
+            style(e.to_string()).red()
+        )),
+        None => pb.finish_with_message(format!("Done: {}", cmd_fmt)),
+    }
 }
 
 pub fn get_progress_bar(m: &MultiProgress) -> ProgressBar {
@@ -67,7 +205,7 @@ pub fn cmd_view(cmd_runner: &SshCmd, pb: ProgressBar, cmd: String) -> Output {
     pb.set_message(format!("Running ssh command {}...", cmd_fmt));
     pb.enable_steady_tick(75);
 
-    let output = cmd_runner.get_output(&cmd);
+    let output = cmd_runner.get_output(&cmd, cmd_runner.timeouts().read);
     let out = output.expect("output");
 
     // let std_out = String::from_utf8_lossy(&out.stdout);
@@ -0,0 +1,206 @@
+use std::{fmt, io, process::Output};
+
+/// ssh errors that won't go away by retrying
+const PERMANENT_SSH_FAILURES: &[&str] = &[
+    "Permission denied",
+    "Host key verification failed",
+    "REMOTE HOST IDENTIFICATION HAS CHANGED",
+    "Bad configuration option",
+    "Too many authentication failures",
+];
+
+/// why a CmdRunner couldn't produce a listing or file
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunnerError {
+    /// the remote path doesn't exist
+    NotFound(String),
+    /// the remote user can't read the path
+    PermissionDenied(String),
+    /// a directory listing was asked for something else
+    NotADirectory(String),
+    /// ssh (or the channel) failed before the command could complete
+    ConnectionLost(String),
+    /// the remote command didn't finish in time
+    Timeout,
+    /// the request was abandoned, eg. its caller went away or we're unmounting
+    Cancelled,
+    /// the command ran but its output couldn't be understood
+    Parse(String),
+    /// the contents received aren't what the remote has, eg. truncated
+    Corrupted(String),
+    /// a command we rely on (ls, cat...) isn't available on the remote
+    CommandMissing(String),
+    /// anything else the remote command complained about
+    Remote(String),
+    /// failed to start or talk to the local process
+    Io(String),
+}
+
+impl RunnerError {
+    /// errno reported back to the kernel, and from there to `ls`, `cp`...
+    pub fn errno(&self) -> libc::c_int {
+        match self {
+            RunnerError::NotFound(_) => libc::ENOENT,
+            RunnerError::PermissionDenied(_) => libc::EACCES,
+            RunnerError::NotADirectory(_) => libc::ENOTDIR,
+            RunnerError::ConnectionLost(_) => libc::EIO,
+            RunnerError::Timeout => libc::ETIMEDOUT,
+            RunnerError::Cancelled => libc::EINTR,
+            RunnerError::Parse(_) => libc::EIO,
+            RunnerError::Corrupted(_) => libc::EIO,
+            RunnerError::CommandMissing(_) => libc::ENOSYS,
+            RunnerError::Remote(_) => libc::EIO,
+            RunnerError::Io(_) => libc::EIO,
+        }
+    }
+
+    /// error for an errno reported by the remote side
+    pub fn from_errno(errno: i32, msg: String) -> Self {
+        match errno {
+            libc::ENOENT => RunnerError::NotFound(msg),
+            libc::EACCES | libc::EPERM => RunnerError::PermissionDenied(msg),
+            libc::ENOTDIR => RunnerError::NotADirectory(msg),
+            libc::ETIMEDOUT => RunnerError::Timeout,
+            _ => RunnerError::Remote(msg),
+        }
+    }
+
+    /// whether trying again later might work, eg. the network dropped as
+    /// opposed to the key being refused
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RunnerError::ConnectionLost(msg) => {
+                !PERMANENT_SSH_FAILURES.iter().any(|p| msg.contains(p))
+            }
+            _ => false,
+        }
+    }
+
+    /// classifies a failed command from its exit status and stderr
+    pub fn from_output(output: &Output) -> Self {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Self::classify(output.status.code(), stderr)
+    }
+
+    fn classify(code: Option<i32>, stderr: String) -> Self {
+        match code {
+            // ssh reports its own failures (refused, reset, auth) with 255
+            Some(255) => return RunnerError::ConnectionLost(stderr),
+            // exit status of a shell that couldn't find the command
+            Some(127) => return RunnerError::CommandMissing(stderr),
+            // killed by a signal, eg. the connection dropping under us
+            None => return RunnerError::ConnectionLost(stderr),
+            _ => {}
+        }
+
+        if stderr.contains("No such file or directory") {
+            RunnerError::NotFound(stderr)
+        } else if stderr.contains("Permission denied") {
+            RunnerError::PermissionDenied(stderr)
+        } else if stderr.contains("Not a directory") {
+            RunnerError::NotADirectory(stderr)
+        } else if stderr.contains("command not found") {
+            RunnerError::CommandMissing(stderr)
+        } else {
+            RunnerError::Remote(stderr)
+        }
+    }
+}
+
+impl fmt::Display for RunnerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunnerError::NotFound(msg) => write!(f, "not found: {}", msg),
+            RunnerError::PermissionDenied(msg) => write!(f, "permission denied: {}", msg),
+            RunnerError::NotADirectory(msg) => write!(f, "not a directory: {}", msg),
+            RunnerError::ConnectionLost(msg) => write!(f, "connection lost: {}", msg),
+            RunnerError::Timeout => write!(f, "timed out"),
+            RunnerError::Cancelled => write!(f, "cancelled"),
+            RunnerError::Parse(msg) => write!(f, "can't parse output: {}", msg),
+            RunnerError::Corrupted(msg) => write!(f, "corrupted transfer: {}", msg),
+            RunnerError::CommandMissing(msg) => write!(f, "remote command missing: {}", msg),
+            RunnerError::Remote(msg) => write!(f, "remote error: {}", msg),
+            RunnerError::Io(msg) => write!(f, "io error: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for RunnerError {
+    fn from(e: io::Error) -> Self {
+        let msg = e.to_string();
+        if e.raw_os_error() == Some(libc::ENOTDIR) {
+            return RunnerError::NotADirectory(msg);
+        }
+
+        match e.kind() {
+            io::ErrorKind::NotFound => RunnerError::NotFound(msg),
+            io::ErrorKind::PermissionDenied => RunnerError::PermissionDenied(msg),
+            io::ErrorKind::TimedOut => RunnerError::Timeout,
+            io::ErrorKind::InvalidData => RunnerError::Parse(msg),
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted => RunnerError::ConnectionLost(msg),
+            _ => RunnerError::Io(msg),
+        }
+    }
+}
+
+#[test]
+fn test_classify() {
+    let classify = |code, stderr: &str| RunnerError::classify(code, stderr.to_string());
+
+    assert_eq!(
+        classify(
+            Some(2),
+            "ls: cannot access '/nope/': No such file or directory"
+        )
+        .errno(),
+        libc::ENOENT
+    );
+    assert_eq!(
+        classify(Some(1), "cat: /root/secret: Permission denied").errno(),
+        libc::EACCES
+    );
+    assert_eq!(
+        classify(Some(2), "ls: cannot access '/etc/passwd/': Not a directory").errno(),
+        libc::ENOTDIR
+    );
+    // an auth failure is ssh's, not the remote file's
+    assert_eq!(
+        classify(Some(255), "user@host: Permission denied (publickey).").errno(),
+        libc::EIO
+    );
+    assert_eq!(
+        classify(
+            Some(255),
+            "ssh: connect to host 10.0.0.1 port 22: Connection refused"
+        ),
+        RunnerError::ConnectionLost(
+            "ssh: connect to host 10.0.0.1 port 22: Connection refused".into()
+        )
+    );
+    assert_eq!(
+        classify(Some(127), "sh: 1: ls: not found").errno(),
+        libc::ENOSYS
+    );
+    assert_eq!(classify(None, "").errno(), libc::EIO);
+    assert_eq!(classify(Some(1), "something odd").errno(), libc::EIO);
+}
+
+#[test]
+fn test_transient() {
+    let lost = |msg: &str| RunnerError::ConnectionLost(msg.into());
+
+    assert!(lost("ssh: connect to host 10.0.0.1 port 22: Connection refused").is_transient());
+    assert!(lost("Connection reset by 10.0.0.1 port 22").is_transient());
+    assert!(
+        lost("ssh: Could not resolve hostname box: Temporary failure in name resolution")
+            .is_transient()
+    );
+    assert!(lost("").is_transient());
+    assert!(!lost("user@host: Permission denied (publickey).").is_transient());
+    assert!(!lost("Host key verification failed.").is_transient());
+    assert!(!RunnerError::NotFound("/nope".into()).is_transient());
+    assert!(!RunnerError::Timeout.is_transient());
+}
@@ -0,0 +1,599 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::cmd::{CmdRunner, SshCmd, Timeouts};
+use crate::error::RunnerError;
+use crate::ls::{dev_numbers, mode_string, FileKind, FileMeta, FileTime};
+use crate::process::Deadline;
+
+/// Agent started on the remote host. It reads one request per line,
+/// `<id> <op> <hex path> [<offset> <len>]`, handles each on its own thread
+/// and answers with a `<id> <errno> <len>\n` header followed by `len` bytes.
+///
+/// `list` and `stat` answer with records of NUL terminated fields, see
+/// the `F_*` indices below.
+const HELPER_SCRIPT: &str = r#"
+import errno, os, stat, sys, threading
+try:
+    import grp, pwd
+except ImportError:
+    grp = pwd = None
+
+out = sys.stdout.buffer
+lock = threading.Lock()
+names = {}
+
+def name_of(db, i):
+    if (db, i) not in names:
+        try:
+            names[(db, i)] = pwd.getpwuid(i).pw_name if db == 'u' else grp.getgrgid(i).gr_name
+        except Exception:
+            names[(db, i)] = str(i)
+    return names[(db, i)]
+
+def kind(m):
+    for test, c in ((stat.S_ISDIR, b'd'), (stat.S_ISLNK, b'l'), (stat.S_ISFIFO, b'p'),
+                    (stat.S_ISSOCK, b's'), (stat.S_ISCHR, b'c'), (stat.S_ISBLK, b'b')):
+        if test(m):
+            return c
+    return b'f'
+
+def record(path, name):
+    st = os.lstat(path)
+    target = os.readlink(path) if stat.S_ISLNK(st.st_mode) else b''
+    fields = [name, kind(st.st_mode), oct(st.st_mode)[2:], st.st_nlink, st.st_uid, st.st_gid,
+              name_of('u', st.st_uid), name_of('g', st.st_gid), st.st_size, st.st_ino,
+              st.st_atime_ns, st.st_mtime_ns, st.st_ctime_ns, st.st_rdev, target]
+    return b''.join((f if isinstance(f, bytes) else str(f).encode()) + b'\0' for f in fields)
+
+def read(path, offset, length):
+    with open(path, 'rb') as f:
+        f.seek(offset)
+        if length < 0:
+            return f.read()
+        chunks = []
+        while length > 0:
+            chunk = f.read(length)
+            if not chunk:
+                break
+            chunks.append(chunk)
+            length -= len(chunk)
+        return b''.join(chunks)
+
+def listing(path):
+    records = []
+    for n in os.listdir(path):
+        try:
+            records.append(record(os.path.join(path, n), n))
+        except FileNotFoundError:
+            # removed since it was listed
+            pass
+    return b''.join(records)
+
+def handle(line):
+    parts = line.split()
+    rid = parts[0] if parts else b'0'
+    try:
+        op, path = parts[1], bytes.fromhex(parts[2].decode())
+        if op == b'list':
+            payload = listing(path)
+        elif op == b'stat':
+            payload = record(path, os.path.basename(path))
+        elif op == b'read':
+            payload = read(path, int(parts[3]), int(parts[4]))
+        elif op == b'readlink':
+            payload = os.readlink(path)
+        else:
+            raise OSError(errno.ENOSYS, 'unknown op')
+        status = 0
+    except (OSError, IOError) as e:
+        status, payload = e.errno or errno.EIO, str(e).encode()
+    except Exception as e:
+        # a malformed request still gets an answer, or its caller would wait
+        # for it until the timeout
+        status, payload = errno.EIO, repr(e).encode()
+    with lock:
+        out.write(b'%s %d %d\n' % (rid, status, len(payload)) + payload)
+        out.flush()
+
+for line in iter(sys.stdin.buffer.readline, b''):
+    threading.Thread(target=handle, args=(line,)).start()
+"#;
+
+// fields of a `list` / `stat` record
+const F_NAME: usize = 0;
+const F_KIND: usize = 1;
+const F_MODE: usize = 2;
+const F_NLINK: usize = 3;
+const F_UID: usize = 4;
+const F_GID: usize = 5;
+const F_USER: usize = 6;
+const F_GROUP: usize = 7;
+const F_SIZE: usize = 8;
+const F_INO: usize = 9;
+const F_ATIME_NS: usize = 10;
+const F_MTIME_NS: usize = 11;
+const F_CTIME_NS: usize = 12;
+const F_RDEV: usize = 13;
+const F_TARGET: usize = 14;
+const FIELDS: usize = 15;
+
+type Reply = Result<Vec<u8>, RunnerError>;
+
+/// how often a waiting request checks for its deadline or cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// least time between two restarts of the helper, requests in between are
+/// served by the ssh commands
+const RESTART_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A CmdRunner talking to `HELPER_SCRIPT` over a single channel. Requests
+/// from concurrent FUSE threads are tagged with ids and multiplexed; a
+/// reader thread hands each response to whoever is waiting for it.
+///
+/// When the channel drops, the helper is started again; if that fails the
+/// request is served by the ssh commands it was connected with.
+pub struct HelperRunner {
+    channel: Mutex<Arc<Channel>>,
+    /// where the helper was started from, to restart it or fall back to
+    ssh: Option<SshCmd>,
+    /// last time the helper was restarted
+    restarted: Mutex<Option<Instant>>,
+    timeouts: Timeouts,
+    /// directory mounted as `/`
+    root: String,
+}
+
+/// one running helper
+struct Channel {
+    writer: Mutex<ChildStdin>,
+    pending: Arc<Mutex<HashMap<u64, mpsc::Sender<Reply>>>>,
+    alive: Arc<AtomicBool>,
+    next_id: AtomicU64,
+    child: Mutex<Child>,
+}
+
+impl HelperRunner {
+    /// starts the helper on the ssh target. fails with `CommandMissing`
+    /// when there's no interpreter for it, so callers can fall back to ls/cat
+    pub fn connect(ssh: &SshCmd) -> Result<Self, RunnerError> {
+        let mut helper = Self::spawn(Self::start(ssh)?)?.with_timeouts(ssh.timeouts());
+        // paths are mapped like the commands would have
+        helper.root = ssh.remote_path("");
+        helper.ssh = Some(ssh.clone());
+        Ok(helper)
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// runs the helper through `cmd`, which should execute the shell
+    /// command given by `helper_cmd`
+    pub fn spawn(cmd: Command) -> Result<Self, RunnerError> {
+        Ok(Self::new(Channel::spawn(cmd)?))
+    }
+
+    fn new(channel: Channel) -> Self {
+        Self {
+            channel: Mutex::new(Arc::new(channel)),
+            ssh: None,
+            restarted: Mutex::new(None),
+            timeouts: Timeouts::default(),
+            root: String::new(),
+        }
+    }
+
+    /// the command starting the helper through `ssh`
+    fn start(ssh: &SshCmd) -> Result<Command, RunnerError> {
+        let probe = ssh.get_output("command -v python3", ssh.timeouts().list)?;
+        if !probe.status.success() || probe.stdout.is_empty() {
+            return Err(RunnerError::CommandMissing("python3".into()));
+        }
+
+        Ok(ssh.remote_command(&helper_cmd("python3")))
+    }
+
+    /// a running channel in place of `dead`, unless the helper was
+    /// restarted too recently to try again
+    fn restart(&self, dead: &Arc<Channel>) -> Option<Arc<Channel>> {
+        let ssh = self.ssh.as_ref()?;
+        let mut channel = self.channel.lock().unwrap();
+        if !Arc::ptr_eq(&channel, dead) {
+            // someone else got there first
+            return Some(channel.clone());
+        }
+
+        let mut restarted = self.restarted.lock().unwrap();
+        if matches!(*restarted, Some(at) if at.elapsed() < RESTART_INTERVAL) {
+            return None;
+        }
+        *restarted = Some(Instant::now());
+
+        match Self::start(ssh).and_then(Channel::spawn) {
+            Ok(started) => {
+                *channel = Arc::new(started);
+                Some(channel.clone())
+            }
+            Err(e) => {
+                println!("Helper restart failed ({}), using ls and cat", e);
+                None
+            }
+        }
+    }
+
+    /// sends a request, restarting the helper once if its channel is gone.
+    /// `ConnectionLost` when it stays down
+    fn request(&self, op: &str, path: &str, range: Option<(u64, i64)>, timeout: Duration) -> Reply {
+        let path = format!("{}{}", self.root, path);
+        let mut line = format!("{} {}", op, hex(path.as_bytes()));
+        if let Some((offset, len)) = range {
+            line += &format!(" {} {}", offset, len);
+        }
+
+        let channel = self.channel.lock().unwrap().clone();
+        match channel.request(&line, timeout) {
+            Err(RunnerError::ConnectionLost(e)) => match self.restart(&channel) {
+                Some(channel) => channel.request(&line, timeout),
+                None => Err(RunnerError::ConnectionLost(e)),
+            },
+            reply => reply,
+        }
+    }
+
+    /// `reply`, or what the ssh commands answer if the helper is down
+    fn or_ssh<T>(
+        &self,
+        reply: Result<T, RunnerError>,
+        fallback: impl FnOnce(&SshCmd) -> Result<T, RunnerError>,
+    ) -> Result<T, RunnerError> {
+        match (reply, &self.ssh) {
+            (Err(RunnerError::ConnectionLost(_)), Some(ssh)) => fallback(ssh),
+            (reply, _) => reply,
+        }
+    }
+}
+
+impl Channel {
+    fn spawn(mut cmd: Command) -> Result<Self, RunnerError> {
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+
+        let writer = child.stdin.take().unwrap();
+        let mut reader = BufReader::new(child.stdout.take().unwrap());
+
+        let pending: Arc<Mutex<HashMap<u64, mpsc::Sender<Reply>>>> = Default::default();
+        let alive = Arc::new(AtomicBool::new(true));
+
+        {
+            let pending = pending.clone();
+            let alive = alive.clone();
+            thread::spawn(move || {
+                while let Some((id, reply)) = read_response(&mut reader) {
+                    if let Some(sender) = pending.lock().unwrap().remove(&id) {
+                        let _ = sender.send(reply);
+                    }
+                }
+
+                // the channel is gone, fail everyone still waiting
+                let mut pending = pending.lock().unwrap();
+                alive.store(false, Ordering::SeqCst);
+                for (_, sender) in pending.drain() {
+                    let _ = sender.send(Err(RunnerError::ConnectionLost("helper exited".into())));
+                }
+            });
+        }
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+            pending,
+            alive,
+            next_id: AtomicU64::new(0),
+            child: Mutex::new(child),
+        })
+    }
+
+    /// sends `<op> <hex path> [<offset> <len>]` and waits for the answer
+    fn request(&self, request: &str, timeout: Duration) -> Reply {
+        let deadline = Deadline::after(timeout);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+
+        {
+            // checked under the lock so the reader can't exit in between
+            let mut pending = self.pending.lock().unwrap();
+            if !self.alive.load(Ordering::SeqCst) {
+                return Err(RunnerError::ConnectionLost("helper exited".into()));
+            }
+            pending.insert(id, sender);
+        }
+
+        let sent = {
+            let mut writer = self.writer.lock().unwrap();
+            writer
+                .write_all(format!("{} {}\n", id, request).as_bytes())
+                .and_then(|_| writer.flush())
+        };
+        if let Err(e) = sent {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(RunnerError::ConnectionLost(e.to_string()));
+        }
+
+        // the helper can't be told to stop, a late reply is simply dropped
+        loop {
+            match receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(reply) => return reply,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if let Err(e) = deadline.check() {
+                        self.pending.lock().unwrap().remove(&id);
+                        return Err(e);
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(RunnerError::ConnectionLost("helper exited".into()))
+                }
+            }
+        }
+    }
+}
+
+impl CmdRunner for HelperRunner {
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        let listing = self
+            .request("list", path, None, self.timeouts.list)
+            .and_then(|payload| parse_records(&payload));
+        self.or_ssh(listing, |ssh| ssh.fetch_path(path))
+    }
+
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+        let reply = self.request("read", path, Some((0, -1)), self.timeouts.read);
+        self.or_ssh(reply, |ssh| ssh.fetch_file(path))
+    }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        let reply = self.request("read", path, Some((offset, len as i64)), self.timeouts.read);
+        self.or_ssh(reply, |ssh| ssh.fetch_range(path, offset, len))
+    }
+
+    fn fetch_meta(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        let meta = self
+            .request("stat", path, None, self.timeouts.list)
+            .and_then(|payload| {
+                parse_records(&payload)?
+                    .pop()
+                    .ok_or_else(|| RunnerError::Parse(format!("no record for {}", path)))
+            });
+        self.or_ssh(meta, |ssh| ssh.fetch_meta(path))
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        let mut child = self.child.lock().unwrap();
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// shell command starting the helper with `interpreter`. the script is hex
+/// encoded so it survives any login shell's quoting rules
+pub fn helper_cmd(interpreter: &str) -> String {
+    format!(
+        "{} -c \"exec(bytes.fromhex('{}').decode())\"",
+        interpreter,
+        hex(HELPER_SCRIPT.as_bytes())
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// reads one `<id> <errno> <len>\n<payload>` response
+fn read_response(reader: &mut impl BufRead) -> Option<(u64, Reply)> {
+    let mut header = String::new();
+    if reader.read_line(&mut header).ok()? == 0 {
+        return None;
+    }
+
+    let mut parts = header.split_whitespace();
+    let id: u64 = parts.next()?.parse().ok()?;
+    let errno: i32 = parts.next()?.parse().ok()?;
+    let len: usize = parts.next()?.parse().ok()?;
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).ok()?;
+
+    let reply = if errno == 0 {
+        Ok(payload)
+    } else {
+        let msg = String::from_utf8_lossy(&payload).into_owned();
+        Err(RunnerError::from_errno(errno, msg))
+    };
+
+    Some((id, reply))
+}
+
+fn parse_records(payload: &[u8]) -> Result<Vec<FileMeta>, RunnerError> {
+    let mut fields = payload
+        .split(|b| *b == 0)
+        .map(|f| String::from_utf8_lossy(f).into_owned())
+        .collect::<Vec<_>>();
+    // every field is NUL terminated, leaving an empty string at the end
+    fields.pop();
+
+    if fields.len() % FIELDS != 0 {
+        return Err(RunnerError::Parse(format!(
+            "{} fields isn't a multiple of {}",
+            fields.len(),
+            FIELDS
+        )));
+    }
+
+    fields.chunks(FIELDS).map(parse_record).collect()
+}
+
+fn parse_record(f: &[String]) -> Result<FileMeta, RunnerError> {
+    let num = |i: usize| {
+        f[i].parse::<u64>()
+            .map_err(|_| RunnerError::Parse(format!("bad field {}: {:?}", i, f[i])))
+    };
+
+    let kind = f[F_KIND]
+        .chars()
+        .next()
+        .and_then(|c| FileKind::from_char(c, &f[F_TARGET], dev_numbers(num(F_RDEV).ok()?)))
+        .ok_or_else(|| RunnerError::Parse(format!("bad kind {:?}", f[F_KIND])))?;
+    let mode = u32::from_str_radix(&f[F_MODE], 8)
+        .map_err(|_| RunnerError::Parse(format!("bad mode {:?}", f[F_MODE])))?;
+    let time = |i: usize| {
+        f[i].parse::<i64>()
+            .map(FileTime::from_nanos)
+            .map_err(|_| RunnerError::Parse(format!("bad time {}: {:?}", i, f[i])))
+    };
+    let modified = time(F_MTIME_NS)?;
+
+    Ok(FileMeta {
+        permissions: mode_string(kind.mode_char(), mode),
+        mode: kind.type_bits() | mode & 0o7777,
+        links: num(F_NLINK)? as u16,
+        owner_name: f[F_USER].clone(),
+        owner_group: f[F_GROUP].clone(),
+        file_size: num(F_SIZE)? as usize,
+        name: f[F_NAME].clone(),
+        modified,
+        uid: Some(num(F_UID)? as u32),
+        gid: Some(num(F_GID)? as u32),
+        inode: Some(num(F_INO)?),
+        accessed: Some(time(F_ATIME_NS)?),
+        changed: Some(time(F_CTIME_NS)?),
+        kind,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn test_helper() {
+    use crate::testing::TempTree;
+    use std::fs;
+
+    if Command::new("python3").arg("--version").output().is_err() {
+        println!("python3 not installed, skipping");
+        return;
+    }
+
+    let root = TempTree::new("helper");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("a file\nwith newline"), "hi").unwrap();
+    let big = (0..200_000u32)
+        .flat_map(|i| i.to_le_bytes())
+        .collect::<Vec<_>>();
+    fs::write(root.join("big.bin"), &big).unwrap();
+    std::os::unix::fs::symlink("sub", root.join("dirlink")).unwrap();
+
+    // goes through a shell exactly like the remote side would
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(helper_cmd("python3"));
+    let runner = Arc::new(HelperRunner::spawn(cmd).unwrap());
+    let root_str = root.to_str().unwrap().to_string();
+
+    let mut dir = runner.fetch_path(&root_str).unwrap();
+    dir.sort_by(|a, b| a.name.cmp(&b.name));
+    let names = dir.iter().map(|m| m.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec!["a file\nwith newline", "big.bin", "dirlink", "sub"]
+    );
+    assert_eq!(dir[0].file_size, 2);
+    assert_eq!(dir[2].kind, FileKind::Symlink("sub".into()));
+    assert!(dir[3].kind.is_dir());
+    assert_eq!(
+        runner
+            .fetch_meta(&format!("{}/sub", root_str))
+            .unwrap()
+            .name,
+        "sub"
+    );
+    assert!(dir[1].modified.secs > 0);
+
+    // concurrent requests share the channel
+    let handles = (0..8)
+        .map(|_| {
+            let runner = runner.clone();
+            let path = format!("{}/big.bin", root_str);
+            thread::spawn(move || runner.fetch_file(&path).unwrap())
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), big);
+    }
+
+    assert_eq!(
+        runner
+            .fetch_range(&format!("{}/big.bin", root_str), 5, 10)
+            .unwrap(),
+        &big[5..15]
+    );
+
+    let missing = format!("{}/missing", root_str);
+    assert_eq!(
+        runner.fetch_file(&missing).unwrap_err().errno(),
+        libc::ENOENT
+    );
+    assert_eq!(
+        runner.fetch_path(&missing).unwrap_err().errno(),
+        libc::ENOENT
+    );
+
+    // a malformed request is answered rather than left to time out
+    assert_eq!(
+        runner
+            .request("read", &missing, None, Duration::from_secs(5))
+            .unwrap_err()
+            .errno(),
+        libc::EIO
+    );
+}
+
+#[test]
+fn test_helper_restart() {
+    use crate::testing::TempTree;
+    use crate::transport::ShellTransport;
+    use std::fs;
+
+    if Command::new("python3").arg("--version").output().is_err() {
+        println!("python3 not installed, skipping");
+        return;
+    }
+
+    let root = TempTree::new("restart");
+    fs::write(root.join("f"), "contents").unwrap();
+
+    let ssh = SshCmd::new(ShellTransport).with_root(root.to_str().unwrap());
+    let runner = HelperRunner::connect(&ssh).unwrap();
+    let kill = |runner: &HelperRunner| {
+        let channel = runner.channel.lock().unwrap().clone();
+        let mut child = channel.child.lock().unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+    };
+    assert_eq!(runner.fetch_file("/f").unwrap(), b"contents");
+
+    // a helper that died is started again
+    kill(&runner);
+    assert_eq!(runner.fetch_file("/f").unwrap(), b"contents");
+    assert_eq!(runner.fetch_path("/").unwrap().len(), 1);
+
+    // and until it may be again, the commands answer instead
+    kill(&runner);
+    assert_eq!(runner.fetch_file("/f").unwrap(), b"contents");
+    assert_eq!(runner.fetch_meta("/f").unwrap().file_size, 8);
+}
@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use crate::cmd::CmdRunner;
+use crate::error::RunnerError;
+use crate::ls::{dev_numbers, mode_string, FileKind, FileMeta, FileTime};
+
+/// A CmdRunner serving a local directory, for exercising the filesystem
+/// without a ssh server. an optional latency is added to every call to
+/// mimic a remote host.
+pub struct LocalRunner {
+    root: PathBuf,
+    latency: Duration,
+}
+
+impl LocalRunner {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            latency: Duration::default(),
+        }
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// maps a mount path (always absolute) into the served directory
+    fn resolve(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_start_matches('/'))
+    }
+
+    fn delay(&self) {
+        if self.latency > Duration::default() {
+            thread::sleep(self.latency);
+        }
+    }
+}
+
+impl CmdRunner for LocalRunner {
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        self.delay();
+
+        let mut dir = vec![];
+        for entry in fs::read_dir(self.resolve(path))? {
+            let entry = entry?;
+            dir.push(to_file_meta(
+                entry.file_name().to_string_lossy().into_owned(),
+                &entry.path(),
+                &entry.metadata()?,
+            ));
+        }
+
+        Ok(dir)
+    }
+
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+        self.delay();
+
+        Ok(fs::read(self.resolve(path))?)
+    }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        self.delay();
+
+        let mut file = fs::File::open(self.resolve(path))?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut contents = vec![];
+        file.take(len).read_to_end(&mut contents)?;
+
+        Ok(contents)
+    }
+
+    fn fetch_meta(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        self.delay();
+
+        let resolved = self.resolve(path);
+        let meta = fs::symlink_metadata(&resolved)?;
+        let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+
+        Ok(to_file_meta(name.into(), &resolved, &meta))
+    }
+}
+
+/// `meta` is of `path` itself, not following links
+fn to_file_meta(name: String, path: &Path, meta: &fs::Metadata) -> FileMeta {
+    let mode = meta.permissions().mode();
+
+    let file_type = meta.file_type();
+    let (major, minor) = dev_numbers(meta.rdev());
+    let kind = if file_type.is_dir() {
+        FileKind::Directory
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(path).unwrap_or_default();
+        FileKind::Symlink(target.to_string_lossy().into_owned())
+    } else if file_type.is_fifo() {
+        FileKind::NamedPipe
+    } else if file_type.is_socket() {
+        FileKind::Socket
+    } else if file_type.is_char_device() {
+        FileKind::CharDevice { major, minor }
+    } else if file_type.is_block_device() {
+        FileKind::BlockDevice { major, minor }
+    } else {
+        FileKind::File
+    };
+
+    FileMeta {
+        permissions: mode_string(kind.mode_char(), mode),
+        mode: kind.type_bits() | mode & 0o7777,
+        kind,
+        links: meta.nlink() as u16,
+        owner_name: meta.uid().to_string(),
+        owner_group: meta.gid().to_string(),
+        file_size: meta.len() as usize,
+        name,
+        modified: FileTime {
+            secs: meta.mtime(),
+            nsecs: meta.mtime_nsec() as u32,
+        },
+        uid: Some(meta.uid()),
+        gid: Some(meta.gid()),
+        inode: Some(meta.ino()),
+        accessed: Some(FileTime {
+            secs: meta.atime(),
+            nsecs: meta.atime_nsec() as u32,
+        }),
+        changed: Some(FileTime {
+            secs: meta.ctime(),
+            nsecs: meta.ctime_nsec() as u32,
+        }),
+        device: Some(meta.dev()),
+        blocks: Some(meta.blocks()),
+        io_block: Some(meta.blksize() as u32),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_local_runner() {
+    use crate::testing::TempTree;
+
+    let root = TempTree::new("local");
+    fs::create_dir_all(root.join("sub dir")).unwrap();
+    fs::write(root.join("sub dir/it's here"), "contents").unwrap();
+    fs::set_permissions(root.join("sub dir"), fs::Permissions::from_mode(0o750)).unwrap();
+
+    let runner = LocalRunner::new(root.to_path_buf());
+
+    let dir = runner.fetch_path("/").unwrap();
+    assert_eq!(dir.len(), 1);
+    assert_eq!(dir[0].name, "sub dir");
+    assert!(dir[0].kind.is_dir());
+    assert_eq!(dir[0].mode & 0o7777, 0o750);
+    assert_eq!(dir[0].permissions, "drwxr-x---");
+
+    let dir = runner.fetch_path("/sub dir/").unwrap();
+    assert_eq!(dir[0].name, "it's here");
+    assert_eq!(dir[0].file_size, 8);
+    assert_eq!(dir[0].kind, FileKind::File);
+
+    let meta = runner.fetch_meta("/sub dir").unwrap();
+    assert_eq!(meta.name, "sub dir");
+    assert_eq!(meta.mode & 0o7777, 0o750);
+    assert_eq!(
+        runner.fetch_meta("/nope").unwrap_err().errno(),
+        libc::ENOENT
+    );
+
+    assert_eq!(
+        runner.fetch_file("/sub dir/it's here").unwrap(),
+        b"contents".to_vec()
+    );
+    assert_eq!(
+        runner.fetch_file("/nope").unwrap_err().errno(),
+        libc::ENOENT
+    );
+    assert_eq!(
+        runner.fetch_path("/sub dir/it's here").unwrap_err().errno(),
+        libc::ENOTDIR
+    );
+}
@@ -1,109 +1,842 @@
-use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use chrono::{Datelike, NaiveDate, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+
+/// a timestamp with sub-second precision
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FileTime {
+    pub secs: i64,
+    pub nsecs: u32,
+}
+
+impl FileTime {
+    /// parses `seconds[.fraction]` as printed by `find -printf %T@`
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(2, '.');
+        let secs = parts.next()?.parse().ok()?;
+        let nsecs = match parts.next() {
+            Some(frac) => {
+                let digits = frac.chars().take(9).collect::<String>();
+                format!("{:0<9}", digits).parse().ok()?
+            }
+            None => 0,
+        };
+
+        Some(Self { secs, nsecs })
+    }
+
+    pub fn from_nanos(nanos: i64) -> Self {
+        Self {
+            secs: nanos.div_euclid(1_000_000_000),
+            nsecs: nanos.rem_euclid(1_000_000_000) as u32,
+        }
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        let since = Duration::new(self.secs.unsigned_abs(), 0);
+        let time = if self.secs < 0 {
+            UNIX_EPOCH - since
+        } else {
+            UNIX_EPOCH + since
+        };
+        time + Duration::new(0, self.nsecs)
+    }
+}
+
+/// file type bits of `st_mode`
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFIFO: u32 = 0o010000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFSOCK: u32 = 0o140000;
+/// Solaris doors and event ports
+pub const S_IFDOOR: u32 = 0o150000;
+pub const S_IFPORT: u32 = 0o160000;
+/// BSD whiteouts. BSD's own value is the one Solaris has for event ports,
+/// so they get one no system uses
+pub const S_IFWHT: u32 = 0o130000;
+
+pub const S_ISUID: u32 = 0o4000;
+pub const S_ISGID: u32 = 0o2000;
+pub const S_ISVTX: u32 = 0o1000;
+
+/// the first character of a `ls -l` mode and the file type it stands for
+const MODE_TYPES: [(char, u32); 10] = [
+    ('-', S_IFREG),
+    ('d', S_IFDIR),
+    ('l', S_IFLNK),
+    ('p', S_IFIFO),
+    ('s', S_IFSOCK),
+    ('c', S_IFCHR),
+    ('b', S_IFBLK),
+    ('D', S_IFDOOR),
+    ('P', S_IFPORT),
+    ('w', S_IFWHT),
+];
+
+/// What an entry is, as the first character of its `ls -l` mode tells
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum FileKind {
+    #[default]
+    File,
+    Directory,
+    /// and where it points
+    Symlink(String),
+    NamedPipe,
+    Socket,
+    CharDevice {
+        major: u32,
+        minor: u32,
+    },
+    BlockDevice {
+        major: u32,
+        minor: u32,
+    },
+}
+
+impl FileKind {
+    /// the kind `c` (a `ls -l` mode's first character, or `find -printf %y`)
+    /// stands for, given the link target and device numbers that go with it.
+    /// doors, event ports and whiteouts can't be read either, they pass
+    /// for sockets
+    pub fn from_char(c: char, target: &str, (major, minor): (u32, u32)) -> Option<Self> {
+        Some(match c {
+            '-' | 'f' => FileKind::File,
+            'd' => FileKind::Directory,
+            'l' => FileKind::Symlink(target.into()),
+            'p' => FileKind::NamedPipe,
+            's' | 'D' | 'P' | 'w' => FileKind::Socket,
+            'c' => FileKind::CharDevice { major, minor },
+            'b' => FileKind::BlockDevice { major, minor },
+            _ => return None,
+        })
+    }
+
+    /// the first character of a `ls -l` mode
+    pub fn mode_char(&self) -> char {
+        match self {
+            FileKind::File => '-',
+            FileKind::Directory => 'd',
+            FileKind::Symlink(_) => 'l',
+            FileKind::NamedPipe => 'p',
+            FileKind::Socket => 's',
+            FileKind::CharDevice { .. } => 'c',
+            FileKind::BlockDevice { .. } => 'b',
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        *self == FileKind::Directory
+    }
+
+    /// the file type bits of `st_mode`
+    pub fn type_bits(&self) -> u32 {
+        match self {
+            FileKind::File => S_IFREG,
+            FileKind::Directory => S_IFDIR,
+            FileKind::Symlink(_) => S_IFLNK,
+            FileKind::NamedPipe => S_IFIFO,
+            FileKind::Socket => S_IFSOCK,
+            FileKind::CharDevice { .. } => S_IFCHR,
+            FileKind::BlockDevice { .. } => S_IFBLK,
+        }
+    }
+
+    /// the device id of device files, 0 for anything else
+    pub fn rdev(&self) -> u64 {
+        match *self {
+            FileKind::CharDevice { major, minor } | FileKind::BlockDevice { major, minor } => {
+                makedev(major, minor)
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// a device id from its major and minor numbers, as glibc encodes them
+pub fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    ((major & 0xffff_f000) << 32)
+        | ((major & 0xfff) << 8)
+        | ((minor & 0xffff_ff00) << 12)
+        | (minor & 0xff)
+}
+
+/// the major and minor numbers of device id `dev`, the other way round
+pub fn dev_numbers(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+    (major as u32, minor as u32)
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct FileMeta {
-    pub directory: bool,
+    pub kind: FileKind,
     pub permissions: String,
-    pub perms: u16,
+    /// the whole `st_mode`: file type, special bits and permissions
+    pub mode: u32,
     pub links: u16,
     pub owner_name: String,
     pub owner_group: String,
     pub file_size: usize,
-    pub month: String,
-    pub date: String,
-    pub time_year: String,
     pub name: String,
-    pub modified_since: u32,
+    /// to the second only when listed by `ls -l` without `--full-time`
+    pub modified: FileTime,
+    /// these are only known when the listing came from something more
+    /// precise than `ls -l`
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub inode: Option<u64>,
+    pub accessed: Option<FileTime>,
+    pub changed: Option<FileTime>,
+    pub born: Option<FileTime>,
+    /// id of the device the file is on
+    pub device: Option<u64>,
+    /// 512 byte blocks allocated
+    pub blocks: Option<u64>,
+    /// preferred size for reads
+    pub io_block: Option<u32>,
 }
 
-pub fn parse_long_list(ls: &str) -> Vec<FileMeta> {
-    let lines = ls.split('\n');
+/// fields printed for each entry by `FIND_PRINTF`, each NUL terminated
+pub const FIND_FIELDS: usize = 15;
 
-    let dir = lines
-        .into_iter()
-        .filter_map(parse_long_list_line)
-        .collect::<Vec<_>>();
+/// `find -printf` format listing everything `ls -l` shows and more, in a
+/// form that doesn't depend on locale or filenames
+pub const FIND_PRINTF: &str = r"%f\0%y\0%Y\0%m\0%U\0%G\0%u\0%g\0%i\0%n\0%s\0%A@\0%T@\0%C@\0%l\0";
 
-    dir
+/// parses the output of `find DIR -mindepth 1 -maxdepth 1 -printf FIND_PRINTF`
+pub fn parse_find_printf(out: &[u8]) -> Vec<FileMeta> {
+    // the trailing NUL leaves an empty field at the end, skipped by chunks_exact
+    split_fields(out)
+        .chunks_exact(FIND_FIELDS)
+        .filter_map(parse_find_record)
+        .collect()
 }
 
-fn parse_long_list_line(line: &str) -> Option<FileMeta> {
-    let mut split = line.trim().split_whitespace();
+/// parses the output of `find DIR -mindepth 1 -printf '%h\0'FIND_PRINTF`,
+/// entries along with the directory they are in
+pub fn parse_find_tree(out: &[u8]) -> Vec<(String, FileMeta)> {
+    split_fields(out)
+        .chunks_exact(FIND_FIELDS + 1)
+        .filter_map(|f| Some((f[0].clone(), parse_find_record(&f[1..])?)))
+        .collect()
+}
+
+fn split_fields(out: &[u8]) -> Vec<String> {
+    out.split(|b| *b == 0)
+        .map(|f| String::from_utf8_lossy(f).into_owned())
+        .collect()
+}
 
-    let permissions = split.next()?.to_string();
-    let links: u16 = split.next()?.parse().ok()?;
-    let owner_name = split.next()?.to_string();
-    let owner_group = split.next()?.to_string();
-    let file_size: usize = split.next()?.parse().unwrap_or(0);
-    let month = split.next()?.to_string();
-    let date = split.next()?.to_string();
-    let time_year = split.next()?.to_string(); // eg. 15:01 / 2018
-    let rest = split.collect::<Vec<_>>().join(" ");
+fn parse_find_record(f: &[String]) -> Option<FileMeta> {
+    // find can't print device numbers
+    let c = f[1].chars().next()?;
+    let kind = FileKind::from_char(c, &f[14], (0, 0))?;
+    // doors and the like keep their own type
+    let (mode_char, type_bits) = MODE_TYPES
+        .iter()
+        .find(|(t, _)| *t == c)
+        .copied()
+        .unwrap_or((kind.mode_char(), kind.type_bits()));
+    let mode = type_bits | u32::from_str_radix(&f[3], 8).ok()? & 0o7777;
+    let modified = FileTime::parse(&f[12])?;
 
+    Some(FileMeta {
+        permissions: mode_string(mode_char, mode),
+        mode,
+        links: f[9].parse().ok()?,
+        owner_name: f[6].clone(),
+        owner_group: f[7].clone(),
+        file_size: f[10].parse().ok()?,
+        name: f[0].clone(),
+        modified,
+        uid: f[4].parse().ok(),
+        gid: f[5].parse().ok(),
+        inode: f[8].parse().ok(),
+        accessed: FileTime::parse(&f[11]),
+        changed: FileTime::parse(&f[13]),
+        kind,
+        ..Default::default()
+    })
+}
+
+/// `ls -l` style permissions, eg. `drwxr-xr-x` or `-rwsr-xr-x`
+pub fn mode_string(kind: char, mode: u32) -> String {
+    let mut s = kind.to_string();
+    for (i, special) in [S_ISUID, S_ISGID, S_ISVTX].iter().enumerate() {
+        let bits = mode >> (6 - 3 * i);
+        s.push(if bits & 4 == 0 { '-' } else { 'r' });
+        s.push(if bits & 2 == 0 { '-' } else { 'w' });
+        // `s` or `t` when executable as well, `S` or `T` when not
+        let (set, unset) = if *special == S_ISVTX {
+            ('t', 'T')
+        } else {
+            ('s', 'S')
+        };
+        s.push(match (mode & special != 0, bits & 1 != 0) {
+            (true, true) => set,
+            (true, false) => unset,
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+/// the whole `st_mode` a `ls -l` mode like `drwxrwxrwt` stands for
+pub fn parse_permissions(permissions: &str) -> Option<u32> {
     let mut chars = permissions.chars();
-    let first_char = chars.next();
-    let is_link = first_char == Some('l');
+    let kind = chars.next()?;
+    let mut mode = MODE_TYPES.iter().find(|(c, _)| *c == kind)?.1;
 
-    let directory = match first_char {
-        Some('d') => true,
-        Some('l') => true, // we fake symbolic links as directories for now to avoid an additional lookup
-        _ => false,
-    };
+    for (i, special) in [S_ISUID, S_ISGID, S_ISVTX].iter().enumerate() {
+        let shift = 6 - 3 * i;
+        match chars.next()? {
+            'r' => mode |= 4 << shift,
+            '-' => {}
+            _ => return None,
+        }
+        match chars.next()? {
+            'w' => mode |= 2 << shift,
+            '-' => {}
+            _ => return None,
+        }
+        let exec = match (chars.next()?, *special) {
+            ('x', _) => 1,
+            ('-', _) => 0,
+            ('s', S_ISUID) | ('s', S_ISGID) | ('t', S_ISVTX) => {
+                mode |= special;
+                1
+            }
+            // `l` and `L` are setgid without group execute, which used
+            // to mean mandatory locking
+            ('S', S_ISUID) | ('S', S_ISGID) | ('l', S_ISGID) | ('L', S_ISGID) | ('T', S_ISVTX) => {
+                mode |= special;
+                0
+            }
+            _ => return None,
+        };
+        mode |= exec << shift;
+    }
+
+    match chars.next() {
+        None => Some(mode),
+        Some(_) => None,
+    }
+}
+
+/// The flavours of `ls -l` output, which differ in what may follow the
+/// mode and in how the date is written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// GNU coreutils: `.` (SELinux context) or `+` (ACL) after the mode,
+    /// dates as `Jun 26 19:08`, or `2021-06-26 19:08` with a `--time-style`
+    Gnu,
+    /// macOS and the BSDs: `@` (extended attributes) or `+` after the mode,
+    /// and in many locales the day before the month: `26 Jun 19:08`
+    Bsd,
+    /// BusyBox: nothing after the mode, and no date at all when built
+    /// without timestamp support
+    Busybox,
+    /// Solaris and illumos: `+` (ACL) after the mode
+    Solaris,
+}
+
+/// each dialect, in the order preferred when several read a listing as well
+pub const DIALECTS: [Dialect; 4] = [
+    Dialect::Gnu,
+    Dialect::Bsd,
+    Dialect::Busybox,
+    Dialect::Solaris,
+];
+
+impl Dialect {
+    /// the dialect of the `ls` a remote probe is about: `GNU` or `BusyBox`
+    /// when `ls --version` says so, otherwise what `uname -s` prints
+    pub fn from_probe(probe: &str) -> Option<Self> {
+        Some(match probe.trim() {
+            "GNU" => Dialect::Gnu,
+            "BusyBox" => Dialect::Busybox,
+            "Darwin" | "FreeBSD" | "OpenBSD" | "NetBSD" | "DragonFly" => Dialect::Bsd,
+            "SunOS" => Dialect::Solaris,
+            _ => return None,
+        })
+    }
+
+    /// the dialect that makes sense of the most lines of `ls`, for remotes
+    /// the probe can't tell
+    pub fn detect(ls: &str) -> Self {
+        let mut best = (Dialect::Gnu, 0);
+        for dialect in DIALECTS.iter() {
+            let parsed = ls
+                .split('\n')
+                .filter(|l| parse_long_list_line(l, *dialect, &ListFormat::utc()).is_some())
+                .count();
+            if parsed > best.1 {
+                best = (*dialect, parsed);
+            }
+        }
+        best.0
+    }
+
+    /// characters `ls` may print right after the mode
+    fn mode_markers(self) -> &'static str {
+        match self {
+            Dialect::Gnu => ".+",
+            Dialect::Bsd => "@+",
+            Dialect::Busybox => "",
+            Dialect::Solaris => "+",
+        }
+    }
+}
+
+/// What the dates in a listing are relative to, and how it's written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListFormat {
+    /// seconds east of UTC of the remote's local time, which `ls` writes
+    /// dates in unless they come with an offset
+    pub utc_offset: i64,
+    /// when the listing was made, to tell the year of dates without one
+    pub now: i64,
+    /// the dialect of the remote's `ls`, when known. otherwise each
+    /// listing is read in the dialect that reads it best
+    pub dialect: Option<Dialect>,
+}
+
+impl ListFormat {
+    /// for a listing made just now by a remote `utc_offset` seconds east
+    /// of UTC
+    pub fn with_offset(utc_offset: i64) -> Self {
+        Self {
+            utc_offset,
+            now: Utc::now().timestamp(),
+            dialect: None,
+        }
+    }
+
+    pub fn utc() -> Self {
+        Self::with_offset(0)
+    }
+
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = Some(dialect);
+        self
+    }
+
+    /// the dialect `ls` is in
+    fn dialect_of(&self, ls: &str) -> Dialect {
+        self.dialect.unwrap_or_else(|| Dialect::detect(ls))
+    }
+}
+
+/// how far in the future a date without a year may be, for clocks that
+/// don't quite agree
+const CLOCK_SKEW: i64 = 24 * 60 * 60;
+
+#[cfg(test)]
+pub fn parse_long_list(ls: &str, format: &ListFormat) -> Vec<FileMeta> {
+    read_long_list(ls, format).0
+}
+
+/// the lines of `ls -l` output that look like entries but can't be read,
+/// eg. with the month names of another language
+pub fn unreadable_lines<'a>(ls: &'a str, format: &ListFormat) -> Vec<&'a str> {
+    read_long_list(ls, format).1
+}
+
+/// the entries of `ls -l` output, and the lines that look like entries
+/// but can't be read
+pub fn read_long_list<'a>(ls: &'a str, format: &ListFormat) -> (Vec<FileMeta>, Vec<&'a str>) {
+    read_long_list_as(ls, format.dialect_of(ls), format)
+}
+
+fn read_long_list_as<'a>(
+    ls: &'a str,
+    dialect: Dialect,
+    format: &ListFormat,
+) -> (Vec<FileMeta>, Vec<&'a str>) {
+    let mut entries = vec![];
+    let mut unreadable = vec![];
+    for line in ls.split('\n') {
+        match parse_long_list_line(line, dialect, format) {
+            Some(meta) => entries.push(meta),
+            // besides blank lines, `total 36`, whatever the language
+            None if line.split_whitespace().count() > 2 => unreadable.push(line),
+            None => {}
+        }
+    }
+    (entries, unreadable)
+}
+
+/// parses the output of `ls -lR`: a `dir:` header for each directory,
+/// followed by its `ls -l` listing and a blank line. directories come in
+/// the order listed, without trailing slashes. anything before the first
+/// header is under an empty name
+pub fn parse_recursive_list(ls: &str, format: &ListFormat) -> Vec<(String, Vec<FileMeta>)> {
+    let dialect = format.dialect_of(ls);
+    let mut sections = vec![];
+    let mut dir = String::new();
+    let mut body = String::new();
+    let mut after_blank = true;
+
+    for line in ls.split('\n') {
+        match line.strip_suffix(':') {
+            Some(header) if after_blank => {
+                if !dir.is_empty() || !body.trim().is_empty() {
+                    sections.push((dir, read_long_list_as(&body, dialect, format).0));
+                }
+                dir = match header.trim_end_matches('/') {
+                    "" => "/".into(),
+                    header => header.into(),
+                };
+                body.clear();
+            }
+            _ => {
+                body.push_str(line);
+                body.push('\n');
+            }
+        }
+        after_blank = line.is_empty();
+    }
 
-    let perms = 0u16
-        + (permissions_octet(&mut chars) << 6)
-        + (permissions_octet(&mut chars) << 3)
-        + permissions_octet(&mut chars);
+    if !dir.is_empty() || !body.trim().is_empty() {
+        sections.push((dir, read_long_list_as(&body, dialect, format).0));
+    }
 
-    let perms = if !is_link { perms } else { 0o7777 };
+    sections
+}
 
-    let name = if is_link {
-        rest.split(" -> ").next().unwrap().to_string()
+fn parse_long_list_line(line: &str, dialect: Dialect, format: &ListFormat) -> Option<FileMeta> {
+    let (mode, rest) = next_word(line)?;
+    let permissions = parse_mode(mode, dialect)?;
+    let mode = parse_permissions(&permissions)?;
+    let (links, rest) = next_word(rest)?;
+    let links: u16 = links.parse().ok()?;
+    let (owner_name, rest) = next_word(rest)?;
+    let (owner_group, rest) = next_word(rest)?;
+
+    // devices show `major, minor` instead of a size (FreeBSD: a hex
+    // number, macOS: a hex minor when it doesn't fit in a byte)
+    let (size, rest) = next_word(rest)?;
+    let hex = |n: &str| match n.strip_prefix("0x") {
+        Some(hex) if dialect == Dialect::Bsd => u32::from_str_radix(hex, 16).ok(),
+        _ => None,
+    };
+    let (file_size, device, rest) = if let Some(major) = size.strip_suffix(',') {
+        let (minor, rest) = next_word(rest)?;
+        let minor = hex(minor).or_else(|| minor.parse().ok())?;
+        (0, (major.parse().ok()?, minor), rest)
+    } else if let Some(dev) = hex(size) {
+        (0, (dev >> 24, dev & 0xff_ffff), rest)
     } else {
-        rest.to_string()
+        (parse_size(size)?, (0, 0), rest)
     };
 
-    let modified_since = parse_time(&month, &date, &time_year)?;
+    let (modified, rest) = match parse_date(rest, dialect, format) {
+        Some(parsed) => parsed,
+        // not when it's a date after all, just not one we can read
+        None if dialect == Dialect::Busybox && !looks_like_date(rest) => {
+            (FileTime::default(), rest)
+        }
+        None => return None,
+    };
+    let rest = rest.trim_start().trim_end_matches('\r');
+    if rest.is_empty() {
+        return None;
+    }
+
+    let first_char = permissions.chars().next()?;
+
+    let (name, target) = match rest.find(" -> ") {
+        Some(i) if first_char == 'l' => (&rest[..i], &rest[i + 4..]),
+        _ => (rest, ""),
+    };
+    // Solaris doors and event ports, BSD whiteouts
+    let kind = FileKind::from_char(first_char, target, device).unwrap_or_default();
 
     Some(FileMeta {
-        directory,
+        kind,
         permissions,
-        perms,
+        mode,
         links,
-        owner_name,
-        owner_group,
+        owner_name: owner_name.into(),
+        owner_group: owner_group.into(),
         file_size,
-        month,
-        date,
-        time_year,
-        name,
-        modified_since,
+        name: name.into(),
+        modified,
+        ..Default::default()
     })
 }
 
-fn permissions_octet(chars: &mut str::Chars) -> u16 {
-    let mut v = 0;
-    match chars.next() {
-        Some('r') => {
-            v += 4;
-        }
-        _ => {}
+/// splits off the first word, leaving the rest with its spacing
+fn next_word(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    let end = s.find(char::is_whitespace).unwrap_or(s.len());
+    match end {
+        0 => None,
+        end => Some((&s[..end], &s[end..])),
     }
-    match chars.next() {
-        Some('w') => {
-            v += 2;
+}
+
+/// the mode without the marker `dialect` may print after it, eg. `drwxr-xr-x@`
+fn parse_mode(mode: &str, dialect: Dialect) -> Option<String> {
+    let mut chars = mode.chars();
+    let permissions = chars.by_ref().take(10).collect::<String>();
+    parse_permissions(&permissions)?;
+    let marker = chars.as_str();
+    if marker.len() > 1 || !dialect.mode_markers().contains(marker) {
+        return None;
+    }
+
+    Some(permissions)
+}
+
+/// whether `s` starts like a date in any language: a word, a day of the
+/// month and a time or year, the first two maybe the other way round
+/// (`Mär  3 23:27`, `3月  3 23:27`, `3. Mai 23:27`)
+fn looks_like_date(s: &str) -> bool {
+    let words = s.split_whitespace().take(3).collect::<Vec<_>>();
+    let is_day = |w: &str| matches!(w.trim_end_matches('.').parse::<u32>(), Ok(1..=31));
+    let is_time_year = |w: &str| w.contains(':') || (w.len() == 4 && w.parse::<u32>().is_ok());
+    match words[..] {
+        [first, second, third] => (is_day(first) || is_day(second)) && is_time_year(third),
+        _ => false,
+    }
+}
+
+/// a size in bytes, or as `ls -h` prints it: `345B`, `6.7K`, `1,2M`
+fn parse_size(size: &str) -> Option<usize> {
+    if let Ok(bytes) = size.parse() {
+        return Some(bytes);
+    }
+
+    let unit = size.chars().last()?;
+    let exponent = match unit.to_ascii_uppercase() {
+        'B' => 0,
+        'K' => 1,
+        'M' => 2,
+        'G' => 3,
+        'T' => 4,
+        'P' => 5,
+        'E' => 6,
+        _ => return None,
+    };
+    let value: f64 = size[..size.len() - 1].replace(',', ".").parse().ok()?;
+
+    Some((value * 1024f64.powi(exponent)).round() as usize)
+}
+
+/// reads the date at the start of `s` in any of the ways `dialect` writes
+/// it, returning it with what follows
+fn parse_date<'a>(
+    s: &'a str,
+    dialect: Dialect,
+    format: &ListFormat,
+) -> Option<(FileTime, &'a str)> {
+    let (first, rest) = next_word(s)?;
+    let (second, rest) = next_word(rest)?;
+
+    let (month, day, rest) = if parse_month(first).is_some() {
+        (first, second, rest)
+    } else if dialect == Dialect::Bsd && parse_month(second).is_some() {
+        (second, first, rest)
+    } else if dialect != Dialect::Bsd && first.contains('-') {
+        return parse_iso_date(first, second, rest, format.utc_offset);
+    } else {
+        return None;
+    };
+
+    let (time_year, rest) = next_word(rest)?;
+    // `ls -T` adds the seconds, and then always the year: `Jun 26 19:08:41 2021`
+    let (year, rest) = if time_year.matches(':').count() == 2 {
+        let (year, rest) = next_word(rest)?;
+        (Some(year), rest)
+    } else {
+        (None, rest)
+    };
+    let secs = parse_time(month, day, time_year, year, format)?;
+
+    Some((FileTime { secs, nsecs: 0 }, rest))
+}
+
+/// `2021-06-26 19:08` as printed with `--time-style=long-iso`, in the
+/// local time `utc_offset` seconds east of UTC, or
+/// `2021-06-26 19:08:41.123456789 +0000` with `full-iso` (BusyBox leaves
+/// out the fraction)
+fn parse_iso_date<'a>(
+    date: &str,
+    time: &str,
+    rest: &'a str,
+    utc_offset: i64,
+) -> Option<(FileTime, &'a str)> {
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    let (hms, frac) = match time.find('.') {
+        Some(i) => (&time[..i], &time[i + 1..]),
+        None => (time, ""),
+    };
+    let mut hms = hms.split(':').map(|t| t.parse::<u32>().ok());
+    let (h, m, s) = (hms.next()??, hms.next()??, hms.next().unwrap_or(Some(0))?);
+    let secs = day.and_hms_opt(h, m, s)?.and_utc().timestamp();
+    let nsecs = FileTime::parse(&format!("0.{}", frac))?.nsecs;
+
+    // a numeric offset only comes along with the seconds
+    let (secs, rest) = if time.matches(':').count() == 2 {
+        let (zone, rest) = next_word(rest)?;
+        (secs - parse_utc_offset(zone)?, rest)
+    } else {
+        (secs - utc_offset, rest)
+    };
+
+    Some((FileTime { secs, nsecs }, rest))
+}
+
+/// parses what GNU `stat PATH` prints, eg.
+///
+/// ```text
+///   File: /vmlinuz -> boot/vmlinuz-5.4.0-1051-aws
+///   Size: 27              Blocks: 0          IO Block: 4096   symbolic link
+/// Device: 10303h/66307d   Inode: 59264       Links: 1
+/// Access: (0777/lrwxrwxrwx)  Uid: (    0/    root)   Gid: (    0/    root)
+/// Access: 2021-06-26 21:55:07.638695727 +0000
+/// Modify: 2021-06-24 06:39:16.664708918 +0000
+/// Change: 2021-06-24 06:39:16.664708918 +0000
+///  Birth: -
+/// ```
+pub fn parse_stat(out: &str) -> Option<FileMeta> {
+    let mut file = None;
+    let mut meta = FileMeta::default();
+    let mut mode = None;
+    let mut modified = None;
+    let mut rdev = (0, 0);
+
+    for line in out.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("File:") {
+            file = Some(value.trim());
+        } else if line.starts_with("Size:") {
+            meta.file_size = stat_word(line, "Size:")?.parse().ok()?;
+            meta.blocks = stat_word(line, "Blocks:").and_then(|b| b.parse().ok());
+            meta.io_block = stat_word(line, "IO Block:").and_then(|b| b.parse().ok());
+        } else if line.starts_with("Device:") {
+            meta.device = stat_word(line, "Device:").and_then(parse_stat_device);
+            meta.inode = stat_word(line, "Inode:").and_then(|i| i.parse().ok());
+            meta.links = stat_word(line, "Links:")?.parse().ok()?;
+            // in hex before coreutils 9, which printed the device as `10303h/66307d`
+            let radix = match stat_word(line, "Device:") {
+                Some(device) if device.contains('/') => 16,
+                _ => 10,
+            };
+            if let Some(numbers) = stat_word(line, "Device type:") {
+                let comma = numbers.find(',')?;
+                rdev = (
+                    u32::from_str_radix(&numbers[..comma], radix).ok()?,
+                    u32::from_str_radix(&numbers[comma + 1..], radix).ok()?,
+                );
+            }
+        } else if line.starts_with("Access: (") {
+            // `(0755/drwxr-xr-x)`
+            let (octal, string) = stat_pair(line, "Access:")?;
+            mode = Some((u32::from_str_radix(octal, 8).ok()?, string.to_string()));
+            let (uid, owner) = stat_pair(line, "Uid:")?;
+            meta.uid = uid.parse().ok();
+            meta.owner_name = owner.into();
+            let (gid, group) = stat_pair(line, "Gid:")?;
+            meta.gid = gid.parse().ok();
+            meta.owner_group = group.into();
+        } else if let Some(time) = line.strip_prefix("Access:") {
+            meta.accessed = parse_stat_time(time);
+        } else if let Some(time) = line.strip_prefix("Modify:") {
+            modified = parse_stat_time(time);
+        } else if let Some(time) = line.strip_prefix("Change:") {
+            meta.changed = parse_stat_time(time);
+        } else if let Some(time) = line.strip_prefix("Birth:") {
+            // `-` when the filesystem doesn't keep it
+            meta.born = parse_stat_time(time);
         }
-        _ => {}
     }
-    match chars.next() {
-        Some('x') => {
-            v += 1;
+
+    let (mode, permissions) = mode?;
+    let modified = modified?;
+    let first_char = permissions.chars().next()?;
+
+    // older versions quote the names: ‘/vmlinuz’ -> ‘boot/...’
+    let unquote = |s: &str| {
+        s.trim_matches(|c| c == '\'' || c == '‘' || c == '’')
+            .to_string()
+    };
+    let (path, target) = match file?.find(" -> ") {
+        Some(i) if first_char == 'l' => {
+            let file = file?;
+            (unquote(&file[..i]), unquote(&file[i + 4..]))
         }
-        _ => {}
+        _ => (unquote(file?), String::new()),
+    };
+
+    Some(FileMeta {
+        kind: FileKind::from_char(first_char, &target, rdev)?,
+        mode: parse_permissions(&permissions)? & S_IFMT | mode & 0o7777,
+        permissions,
+        name: match path.rsplit('/').next() {
+            Some("") | None => path.clone(),
+            Some(name) => name.into(),
+        },
+        modified,
+        ..meta
+    })
+}
+
+/// the word after `label` in a `stat` line
+fn stat_word<'a>(line: &'a str, label: &str) -> Option<&'a str> {
+    let start = line.find(label)? + label.len();
+    next_word(&line[start..]).map(|(word, _)| word)
+}
+
+/// the two halves of `label (a/b)` in a `stat` line, trimmed
+fn stat_pair<'a>(line: &'a str, label: &str) -> Option<(&'a str, &'a str)> {
+    let start = line.find(label)? + label.len();
+    let value = line[start..].trim_start().strip_prefix('(')?;
+    let value = &value[..value.find(')')?];
+    let slash = value.find('/')?;
+    Some((value[..slash].trim(), value[slash + 1..].trim()))
+}
+
+/// `10303h/66307d`, or `259,1` since coreutils 9
+fn parse_stat_device(device: &str) -> Option<u64> {
+    if let Some(slash) = device.find('/') {
+        return device[slash + 1..].trim_end_matches('d').parse().ok();
+    }
+
+    let comma = device.find(',')?;
+    Some(makedev(
+        device[..comma].parse().ok()?,
+        device[comma + 1..].parse().ok()?,
+    ))
+}
+
+/// `2021-06-24 06:39:24.292540575 +0000`
+fn parse_stat_time(time: &str) -> Option<FileTime> {
+    let (date, rest) = next_word(time)?;
+    let (time, rest) = next_word(rest)?;
+    parse_iso_date(date, time, rest, 0).map(|(modified, _)| modified)
+}
+
+/// `+0200` or `-0430`, as printed by `date +%z`, in seconds east of UTC
+pub fn parse_utc_offset(zone: &str) -> Option<i64> {
+    let sign = match zone.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let digits = &zone[1..];
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
-    v
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 3600 + minutes * 60))
 }
 
 #[test]
@@ -137,22 +870,38 @@ fn test_ubuntu() {
     lrwxrwxrwx   1 root root    27 Jun 24 06:39 vmlinuz.old -> boot/vmlinuz-5.4.0-1049-aws
     ";
 
-    let dir = parse_long_list(sample);
+    let dir = parse_long_list(sample, &ListFormat::utc());
 
     assert_eq!(dir.len(), 26);
 
-    // this not technically right because of the symlinks
     assert_eq!(
-        dir.iter().filter(|m| m.directory).collect::<Vec<_>>().len(),
-        26
+        dir.iter()
+            .filter(|m| m.kind.is_dir())
+            .collect::<Vec<_>>()
+            .len(),
+        22
     );
+    let links = dir
+        .iter()
+        .filter_map(|m| match &m.kind {
+            FileKind::Symlink(target) => Some((m.name.as_str(), target.as_str())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(links.len(), 4);
+    assert_eq!(links[0], ("initrd.img", "boot/initrd.img-5.4.0-1051-aws"));
+    assert_eq!(dir[5].mode & 0o7777, 0o777);
+    assert_eq!(dir[5].mode, 0o120777);
+    // sticky /tmp
+    assert_eq!(dir[21].mode & 0o7777, 0o1777);
+    assert_eq!(dir[21].mode, S_IFDIR | 0o1777);
 }
 
 #[test]
 fn test_parse_err() {
     let sample = r"ls: cannot access '/fdasfksahfjkdsa': No such file or directory";
 
-    let dir = parse_long_list(sample);
+    let dir = parse_long_list(sample, &ListFormat::utc());
     assert_eq!(dir.len(), 0);
 }
 
@@ -168,13 +917,235 @@ fn test_mac() {
     -rwxr-xr-x  1 zz85  staff   128B 26 Jun 15:52 test.sh
     ";
 
-    let dir = parse_long_list(sample);
+    let dir = parse_long_list(sample, &ListFormat::utc());
 
     assert_eq!(dir.len(), 7);
     assert_eq!(
-        dir.iter().filter(|m| m.directory).collect::<Vec<_>>().len(),
+        dir.iter()
+            .filter(|m| m.kind.is_dir())
+            .collect::<Vec<_>>()
+            .len(),
         2
     );
+
+    assert_eq!(dir[0].file_size, 6861);
+    assert_eq!(dir[1].file_size, 345);
+    assert_eq!(dir[5].name, "target");
+    assert_eq!(dir[5].permissions, "drwxr-xr-x");
+    assert_eq!(
+        parse_time("Jun", "26", "19:08", None, &ListFormat::utc()),
+        Some(dir[0].modified.secs)
+    );
+}
+
+#[test]
+fn test_locales() {
+    let utc = ListFormat::utc();
+
+    // glibc writes the day first in German, `3. Mai`, and the month as a
+    // number in Japanese
+    let de = include_str!("../fixtures/ls/de_DE.txt");
+    let (dir, unreadable) = read_long_list(de, &utc);
+    assert!(dir.is_empty());
+    assert_eq!(unreadable.len(), 6);
+    assert!(unreadable[0].ends_with(" 3. Mai 23:27 bin"));
+
+    let ja = include_str!("../fixtures/ls/ja_JP.txt");
+    assert!(parse_long_list(ja, &utc).is_empty());
+    assert_eq!(unreadable_lines(ja, &utc).len(), 6);
+
+    let c = include_str!("../fixtures/ls/gnu.txt");
+    assert!(unreadable_lines(c, &utc).is_empty());
+}
+
+#[test]
+fn test_dialects() {
+    // each fixture, with what the probe prints on the host it comes from
+    let fixtures = [
+        (
+            include_str!("../fixtures/ls/gnu.txt"),
+            "GNU",
+            Dialect::Gnu,
+            9,
+        ),
+        (
+            include_str!("../fixtures/ls/gnu_full_time.txt"),
+            "GNU",
+            Dialect::Gnu,
+            9,
+        ),
+        (
+            include_str!("../fixtures/ls/macos.txt"),
+            "Darwin",
+            Dialect::Bsd,
+            6,
+        ),
+        (
+            include_str!("../fixtures/ls/macos_locale.txt"),
+            "Darwin",
+            Dialect::Bsd,
+            7,
+        ),
+        (
+            include_str!("../fixtures/ls/freebsd.txt"),
+            "FreeBSD",
+            Dialect::Bsd,
+            5,
+        ),
+        (
+            include_str!("../fixtures/ls/busybox.txt"),
+            "BusyBox",
+            Dialect::Busybox,
+            5,
+        ),
+        (
+            include_str!("../fixtures/ls/busybox_notime.txt"),
+            "BusyBox",
+            Dialect::Busybox,
+            4,
+        ),
+        (
+            include_str!("../fixtures/ls/solaris.txt"),
+            "SunOS",
+            Dialect::Solaris,
+            5,
+        ),
+    ];
+    let summary = |dir: Vec<FileMeta>| {
+        dir.into_iter()
+            .map(|m| (m.name, m.permissions, m.kind, m.file_size))
+            .collect::<Vec<_>>()
+    };
+
+    for (ls, probe, dialect, entries) in fixtures.iter() {
+        assert_eq!(Dialect::from_probe(probe), Some(*dialect));
+        let (dir, unreadable) = read_long_list(ls, &ListFormat::utc().with_dialect(*dialect));
+        assert_eq!(dir.len(), *entries, "{}", ls);
+        assert!(unreadable.is_empty(), "{}", ls);
+
+        // without a probe, the listing is read the same
+        assert_eq!(
+            summary(parse_long_list(ls, &ListFormat::utc())),
+            summary(dir),
+            "{}",
+            ls
+        );
+    }
+    assert_eq!(Dialect::from_probe("Linux\n"), None);
+
+    // the listing alone tells these apart. BusyBox's with dates and
+    // Solaris' can't be told from GNU's, being written the same
+    assert_eq!(
+        Dialect::detect(include_str!("../fixtures/ls/gnu.txt")),
+        Dialect::Gnu
+    );
+    assert_eq!(
+        Dialect::detect(include_str!("../fixtures/ls/macos.txt")),
+        Dialect::Bsd
+    );
+    assert_eq!(
+        Dialect::detect(include_str!("../fixtures/ls/freebsd.txt")),
+        Dialect::Bsd
+    );
+    assert_eq!(
+        Dialect::detect(include_str!("../fixtures/ls/busybox_notime.txt")),
+        Dialect::Busybox
+    );
+
+    // markers only some dialects print
+    let utc = ListFormat::utc();
+    let gnu = parse_long_list(include_str!("../fixtures/ls/gnu.txt"), &utc);
+    assert_eq!(gnu[0].permissions, "-rw-r--r--");
+    assert_eq!(gnu[0].name, "acl file");
+    assert_eq!(gnu[0].owner_name, "cloudsdk");
+    let selinux = parse_long_list("drwxr-xr-x. 2 root root 4096 Mar  3 23:27 bin", &utc);
+    assert_eq!(selinux[0].permissions, "drwxr-xr-x");
+    assert_eq!(gnu[7].name, "two  spaces");
+    assert_eq!(gnu[8].name, "vmlinuz");
+    assert_eq!(
+        gnu[8].kind,
+        FileKind::Symlink("boot/vmlinuz-5.4.0-1051-aws".into())
+    );
+    // devices have numbers instead of a size
+    assert_eq!(gnu[4].name, "null");
+    assert_eq!(gnu[4].kind, FileKind::CharDevice { major: 1, minor: 3 });
+    assert_eq!(
+        gnu[5].kind,
+        FileKind::BlockDevice {
+            major: 259,
+            minor: 0
+        }
+    );
+    assert_eq!(gnu[5].file_size, 0);
+    assert_eq!(gnu[2].kind, FileKind::Socket);
+    assert_eq!(gnu[3].kind, FileKind::NamedPipe);
+    assert_eq!(gnu[6].kind, FileKind::Directory);
+    assert_eq!(gnu[6].permissions, "drwxrwxrwt");
+    assert!(
+        parse_long_list(
+            include_str!("../fixtures/ls/macos.txt"),
+            &utc.with_dialect(Dialect::Gnu)
+        )
+        .len()
+            < 6
+    );
+
+    let full = parse_long_list(include_str!("../fixtures/ls/gnu_full_time.txt"), &utc);
+    assert_eq!(full[1].modified.secs, 1614814031);
+    assert_eq!(
+        full[0].modified,
+        FileTime {
+            secs: 1624734521,
+            nsecs: 123456789
+        }
+    );
+    // `--time-style=long-iso`
+    let long_iso = parse_long_list("drwxr-xr-x 2 root root 4096 2021-03-03 23:27 bin", &utc);
+    assert_eq!(long_iso[0].modified.secs, 1614814020);
+
+    let mac = parse_long_list(include_str!("../fixtures/ls/macos.txt"), &utc);
+    assert_eq!(mac[2].name, "target");
+    assert_eq!(mac[3].kind, FileKind::Symlink("Cargo.toml".into()));
+    assert_eq!(mac[4].kind, FileKind::CharDevice { major: 3, minor: 2 });
+    assert_eq!(mac[5].name, "old file");
+    let tty = "crw-rw-rw-  1 root  wheel   16, 0x00000100 Jun 26 15:52 ttys256";
+    assert_eq!(
+        parse_long_list(tty, &utc.with_dialect(Dialect::Bsd))[0].kind,
+        FileKind::CharDevice {
+            major: 16,
+            minor: 256
+        }
+    );
+
+    let freebsd = parse_long_list(include_str!("../fixtures/ls/freebsd.txt"), &utc);
+    assert_eq!(freebsd[1].name, "ada0");
+    assert!(matches!(freebsd[1].kind, FileKind::CharDevice { .. }));
+
+    let busybox = parse_long_list(include_str!("../fixtures/ls/busybox.txt"), &utc);
+    assert_eq!(busybox[3].owner_name, "1000");
+    assert_eq!(busybox[3].name, "owned by uid");
+    assert_eq!(busybox[2].kind, FileKind::Symlink("/bin/busybox".into()));
+
+    let notime = parse_long_list(include_str!("../fixtures/ls/busybox_notime.txt"), &utc);
+    assert_eq!(notime[0].name, "bin");
+    assert!(notime[0].kind.is_dir());
+    assert_eq!(notime[2].name, "sh");
+    assert_eq!(notime[3].file_size, 345);
+    assert_eq!(notime[3].modified.secs, 0);
+
+    let solaris = parse_long_list(
+        include_str!("../fixtures/ls/solaris.txt"),
+        &utc.with_dialect(Dialect::Solaris),
+    );
+    assert_eq!(solaris.len(), 5);
+    assert_eq!(solaris[1].name, "acl file");
+    assert_eq!(solaris[3].permissions, "Drw-r--r--");
+    assert_eq!(solaris[3].kind, FileKind::Socket);
+    assert_eq!(solaris[3].mode, S_IFDOOR | 0o644);
+
+    assert_eq!(parse_size("1,5M"), Some(1572864));
+    assert_eq!(parse_size("12"), Some(12));
+    assert_eq!(parse_size("x"), None);
 }
 
 fn parse_month(month: &str) -> Option<u32> {
@@ -196,37 +1167,119 @@ fn parse_month(month: &str) -> Option<u32> {
     Some(v + 1)
 }
 
-fn parse_time(month: &str, day: &str, time_year: &str) -> Option<u32> {
-    let has_time = time_year.contains(':');
-
-    let now = Utc::now();
+/// the time `ls` means by `Jun 26 19:08` or `Jul 31  2019`, the year
+/// coming separately with `ls -T`
+fn parse_time(
+    month: &str,
+    day: &str,
+    time_year: &str,
+    year: Option<&str>,
+    format: &ListFormat,
+) -> Option<i64> {
+    let month = parse_month(month)?;
+    let day = day.parse().ok()?;
+    let at = |year, h, m, s| {
+        let local = NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(h, m, s)?;
+        Some(local.and_utc().timestamp() - format.utc_offset)
+    };
 
-    let ts = if has_time {
-        let day = day.parse().ok()?;
-        let month = parse_month(month)?;
-        let mut time = time_year.split(':').filter_map(|t| t.parse().ok());
-        NaiveDate::from_ymd(now.year(), month, day).and_hms(time.next()?, time.next()?, 0)
-    } else {
-        let day = day.parse().ok()?;
-        let month = parse_month(month)?;
-        let year = time_year.parse().ok()?;
+    if !time_year.contains(':') {
+        return at(time_year.parse().ok()?, 0, 0, 0);
+    }
 
-        NaiveDate::from_ymd(year, month, day).and_hms(0, 0, 0)
-    };
+    let mut time = time_year.split(':').map(|t| t.parse().ok());
+    let (h, m, s) = (
+        time.next()??,
+        time.next()??,
+        time.next().unwrap_or(Some(0))?,
+    );
+    if let Some(year) = year {
+        return at(year.parse().ok()?, h, m, s);
+    }
 
-    Some(ts.timestamp() as u32)
+    // the year is left out for dates in the past six months, so it's the
+    // last one when this year's would still be to come
+    let this_year = DateTime::from_timestamp(format.now + format.utc_offset, 0)?.year();
+    match at(this_year, h, m, s) {
+        Some(secs) if secs <= format.now + CLOCK_SKEW => Some(secs),
+        _ => at(this_year - 1, h, m, s),
+    }
 }
+
 #[test]
 fn test_time_parsing() {
-    assert!(parse_time("Jun", "26", "19:08").is_some());
-    assert!(parse_time("Jul", "31", "2019").is_some());
+    // 2022-01-10 12:00 UTC
+    let utc = ListFormat {
+        utc_offset: 0,
+        now: 1641816000,
+        dialect: None,
+    };
+    let time = |month, day, time_year, format| parse_time(month, day, time_year, None, format);
+
+    // recent dates are from the last six months, across new year too
+    assert_eq!(time("Dec", "20", "10:00", &utc), Some(1639994400));
+    assert_eq!(time("Jan", "9", "08:00", &utc), Some(1641715200));
+    assert_eq!(time("Mar", "1", "00:00", &utc), Some(1614556800));
+    // a bit ahead, as when the clocks disagree
+    assert_eq!(time("Jan", "11", "08:00", &utc), Some(1641888000));
+
+    let tokyo = ListFormat {
+        utc_offset: 9 * 3600,
+        ..utc
+    };
+    assert_eq!(time("Jan", "10", "20:00", &tokyo), Some(1641812400));
+
+    // still 2021 in New York at 2022-01-01 00:30 UTC
+    let new_york = ListFormat {
+        utc_offset: -5 * 3600,
+        now: 1640997000,
+        ..utc
+    };
+    assert_eq!(time("Dec", "31", "19:00", &new_york), Some(1640995200));
+
+    let paris = ListFormat {
+        utc_offset: 2 * 3600,
+        ..utc
+    };
+    assert_eq!(time("Jul", "31", "2019", &paris), Some(1564524000));
+    assert_eq!(time("Jan", "1", "1960", &utc), Some(-315619200));
+    assert_eq!(time("Jan", "19", "2040", &utc), Some(2210544000));
+    assert_eq!(time("Feb", "30", "2019", &utc), None);
+
+    // `ls -T`
+    assert_eq!(
+        parse_time("Jun", "26", "19:08:41", Some("2021"), &utc),
+        Some(1624734521)
+    );
+    let dir = parse_long_list(
+        "-rw-r--r--  1 zz85  staff  345 Jun 26 19:08:41 2021 Cargo.toml\n\
+         -rw-r--r--  1 zz85  staff  611 26 Jun 19:08:41 2021 2021\n",
+        &utc,
+    );
+    assert_eq!(dir[0].modified.secs, 1624734521);
+    assert_eq!(dir[0].name, "Cargo.toml");
+    assert_eq!(dir[1].modified.secs, 1624734521);
+    assert_eq!(dir[1].name, "2021");
+
+    // `long-iso` is in local time, `full-iso` says which
+    let dir = parse_long_list(
+        "-rw-r--r-- 1 zz85 zz85 345 2021-06-26 19:08 Cargo.toml\n\
+         -rw-r--r-- 1 zz85 zz85 611 2021-06-26 19:08:41 +0000 README.md\n",
+        &paris,
+    );
+    assert_eq!(dir[0].modified.secs, 1624730880 - 3600);
+    assert_eq!(dir[1].modified.secs, 1624734521);
+
+    assert_eq!(parse_utc_offset("+0530"), Some(19800));
+    assert_eq!(parse_utc_offset("-0800"), Some(-28800));
+    assert_eq!(parse_utc_offset("+0000"), Some(0));
+    assert_eq!(parse_utc_offset("UTC"), None);
+    assert_eq!(parse_utc_offset("+05:30"), None);
 }
 
 #[test]
 fn test_stat() {
-    // TODO parse stat output
-
-    let _stat = r"stat /bin
+    let stat = r"stat /bin
 File: /bin
 Size: 4096            Blocks: 8          IO Block: 4096   directory
 Device: 10303h/66307d   Inode: 12          Links: 2
@@ -236,7 +1289,7 @@ Modify: 2021-03-03 23:27:41.025619169 +0000
 Change: 2021-03-03 23:27:41.025619169 +0000
 Birth: -";
 
-    let _stat_ln = r"File: /vmlinuz -> boot/vmlinuz-5.4.0-1051-aws
+    let stat_ln = r"File: /vmlinuz -> boot/vmlinuz-5.4.0-1051-aws
 Size: 27              Blocks: 0          IO Block: 4096   symbolic link
 Device: 10303h/66307d   Inode: 59264       Links: 1
 Access: (0777/lrwxrwxrwx)  Uid: (    0/    root)   Gid: (    0/    root)
@@ -245,7 +1298,7 @@ Modify: 2021-06-24 06:39:16.664708918 +0000
 Change: 2021-06-24 06:39:16.664708918 +0000
 Birth: -";
 
-    let _stat_linked = r"stat /boot/initrd.img-5.4.0-1051-aws
+    let stat_linked = r"stat /boot/initrd.img-5.4.0-1051-aws
 File: /boot/initrd.img-5.4.0-1051-aws
 Size: 21246462        Blocks: 41504      IO Block: 4096   regular file
 Device: 10303h/66307d   Inode: 146138      Links: 1
@@ -254,6 +1307,100 @@ Access: 2021-06-24 06:39:24.292540575 +0000
 Modify: 2021-06-24 06:39:24.260541281 +0000
 Change: 2021-06-24 06:39:24.288540664 +0000
 Birth: -";
+
+    let bin = parse_stat(stat).unwrap();
+    assert_eq!(bin.name, "bin");
+    assert_eq!(bin.kind, FileKind::Directory);
+    assert_eq!(bin.permissions, "drwxr-xr-x");
+    assert_eq!(bin.mode & 0o7777, 0o755);
+    assert_eq!(bin.file_size, 4096);
+    assert_eq!(bin.blocks, Some(8));
+    assert_eq!(bin.io_block, Some(4096));
+    assert_eq!(bin.device, Some(66307));
+    assert_eq!(bin.inode, Some(12));
+    assert_eq!(bin.links, 2);
+    assert_eq!((bin.uid, bin.owner_name.as_str()), (Some(0), "root"));
+    assert_eq!((bin.gid, bin.owner_group.as_str()), (Some(0), "root"));
+    assert_eq!(
+        bin.modified,
+        FileTime {
+            secs: 1614814061,
+            nsecs: 25619169
+        }
+    );
+    assert_eq!(
+        bin.accessed,
+        Some(FileTime {
+            secs: 1624694702,
+            nsecs: 904641271
+        })
+    );
+    assert_eq!(bin.born, None);
+
+    let link = parse_stat(stat_ln).unwrap();
+    assert_eq!(link.name, "vmlinuz");
+    assert_eq!(
+        link.kind,
+        FileKind::Symlink("boot/vmlinuz-5.4.0-1051-aws".into())
+    );
+    assert_eq!(link.permissions, "lrwxrwxrwx");
+    assert_eq!(link.file_size, 27);
+    assert_eq!(link.inode, Some(59264));
+
+    let linked = parse_stat(stat_linked).unwrap();
+    assert_eq!(linked.name, "initrd.img-5.4.0-1051-aws");
+    assert_eq!(linked.kind, FileKind::File);
+    assert_eq!(linked.mode & 0o7777, 0o644);
+    assert_eq!(linked.file_size, 21246462);
+    assert_eq!(linked.blocks, Some(41504));
+    assert_eq!(
+        linked.changed,
+        Some(FileTime {
+            secs: 1624516764,
+            nsecs: 288540664
+        })
+    );
+
+    // as newer and older coreutils print them
+    let newer = r"  File: /dev/null
+  Size: 0         	Blocks: 0          IO Block: 4096   character special file
+Device: 0,5	Inode: 4           Links: 1     Device type: 1,3
+Access: (0666/crw-rw-rw-)  Uid: (    0/    root)   Gid: (    0/    root)
+Access: 2021-06-26 21:55:07.638695727 +0100
+Modify: 2021-06-26 21:55:07.638695727 +0100
+Change: 2021-06-26 21:55:07.638695727 +0100
+ Birth: 2021-06-20 08:00:00.000000000 +0000";
+    let null = parse_stat(newer).unwrap();
+    assert_eq!(null.name, "null");
+    assert_eq!(null.device, Some(5));
+    assert_eq!(null.kind, FileKind::CharDevice { major: 1, minor: 3 });
+    assert_eq!(null.modified.secs, 1624740907);
+    assert_eq!(null.born.map(|b| b.secs), Some(1624176000));
+
+    let quoted = stat_ln.replace(
+        "/vmlinuz -> boot/vmlinuz-5.4.0-1051-aws",
+        "‘/vmlinuz’ -> ‘boot/vmlinuz-5.4.0-1051-aws’",
+    );
+    let link = parse_stat(&quoted).unwrap();
+    assert_eq!(link.name, "vmlinuz");
+    assert_eq!(
+        link.kind,
+        FileKind::Symlink("boot/vmlinuz-5.4.0-1051-aws".into())
+    );
+
+    // device numbers were in hex
+    let older = newer
+        .replace("Device: 0,5", "Device: 5h/5d")
+        .replace("Device type: 1,3", "Device type: 103,a");
+    assert_eq!(
+        parse_stat(&older).unwrap().kind,
+        FileKind::CharDevice {
+            major: 259,
+            minor: 10
+        }
+    );
+
+    assert!(parse_stat("stat: cannot statx '/nope': No such file or directory").is_none());
 }
 
 #[test]
@@ -262,7 +1409,7 @@ fn test_perms() {
     drwxr-xr-x   2 root root  4096 Mar  3 23:27 bin
     ";
 
-    let dir = parse_long_list(sample);
+    let dir = parse_long_list(sample, &ListFormat::utc());
     assert_eq!(dir.len(), 1);
 
     let file = &dir[0];
@@ -276,5 +1423,214 @@ fn test_perms() {
     let oct: u16 = 0o0755;
 
     assert_eq!(bytes, oct);
-    assert_eq!(bytes, file.perms);
+    assert_eq!(bytes as u32, file.mode & 0o7777);
+    assert_eq!(file.mode, 0o040755);
+
+    let table = [
+        ("-rwsr-xr-x", 0o104755),
+        ("-rwSr--r--", 0o104644),
+        ("drwxr-sr-x", 0o042755),
+        ("-rw-r-Sr--", 0o102644),
+        ("drwxrwxrwt", 0o041777),
+        ("drwxrwx--T", 0o041770),
+        ("-rwsrwsrwt", 0o107777),
+        ("-rwSrwSrwT", 0o107666),
+        ("----------", 0o100000),
+        ("crw-rw-rw-", 0o020666),
+        ("brw-rw----", 0o060660),
+        ("prw-r--r--", 0o010644),
+        ("srwxrwxrwx", 0o140777),
+        ("lrwxrwxrwx", 0o120777),
+        ("Drw-r--r--", 0o150644),
+        // mandatory locking, as older systems show setgid without group execute
+        ("-rw-r-lr--", 0o102644),
+        ("-rw-r-Lr--", 0o102644),
+    ];
+    for (permissions, mode) in table.iter() {
+        assert_eq!(
+            parse_permissions(permissions),
+            Some(*mode),
+            "{}",
+            permissions
+        );
+    }
+
+    for bad in [
+        "",
+        "-rwxr-xr-",
+        "-rwxr-xr-x-",
+        "?rwxr-xr-x",
+        "-rwtr-xr-x",
+        "-rwxr-xr-s",
+        "-rwxr-tr-x",
+        "-rwlr-xr-x",
+        "-wrxr-xr-x",
+    ]
+    .iter()
+    {
+        assert_eq!(parse_permissions(bad), None, "{}", bad);
+    }
+
+    // every type and every combination of permission and special bits
+    for (kind, type_bits) in MODE_TYPES.iter() {
+        for bits in 0..=0o7777 {
+            let permissions = mode_string(*kind, bits);
+            assert_eq!(
+                parse_permissions(&permissions),
+                Some(type_bits | bits),
+                "{}",
+                permissions
+            );
+        }
+    }
+
+    let dir = parse_long_list(
+        "-rwsr-xr-x  1 root root 68208 Mar 14  2020 passwd\n\
+         -rwxr-sr-x  1 root tty  35048 Mar 14  2020 wall\n",
+        &ListFormat::utc(),
+    );
+    assert_eq!(dir[0].mode & 0o7777, 0o4755);
+    assert_eq!(dir[0].mode, S_IFREG | 0o4755);
+    assert_eq!(dir[1].mode & 0o7777, 0o2755);
+}
+
+#[test]
+fn test_find_printf() {
+    let sample = b"bin\0d\0d\x00755\x000\x000\0root\0root\x0012\x002\x004096\x001624694702.9046412710\x001614814061.0256191690\x001614814061.0256191690\0\0\
+vmlinuz\0l\0f\x00777\x000\x000\0root\0root\x0059264\x001\x0027\x001624744507.6386957270\x001624516756.6647089180\x001624516756.6647089180\0boot/vmlinuz-5.4.0-1051-aws\0\
+a file\nwith newline\0f\0f\x004755\x001000\x001000\0zz85\0staff\x00146138\x001\x0021246462\x001624516764.2925405750\x001624516764.2605412810\x001624516764.2885406640\0\0";
+
+    let dir = parse_find_printf(sample);
+    assert_eq!(dir.len(), 3);
+
+    let bin = &dir[0];
+    assert_eq!(bin.name, "bin");
+    assert_eq!(bin.kind, FileKind::Directory);
+    assert_eq!(bin.permissions, "drwxr-xr-x");
+    assert_eq!(bin.mode & 0o7777, 0o755);
+    assert_eq!(bin.inode, Some(12));
+    assert_eq!(bin.links, 2);
+    assert_eq!(
+        bin.modified,
+        FileTime {
+            secs: 1614814061,
+            nsecs: 25619169
+        }
+    );
+    assert_eq!(
+        bin.accessed,
+        Some(FileTime {
+            secs: 1624694702,
+            nsecs: 904641271
+        })
+    );
+
+    // links are reported as such, even to directories
+    let link = &dir[1];
+    assert_eq!(link.permissions, "lrwxrwxrwx");
+    assert_eq!(
+        link.kind,
+        FileKind::Symlink("boot/vmlinuz-5.4.0-1051-aws".into())
+    );
+
+    let file = &dir[2];
+    assert_eq!(file.name, "a file\nwith newline");
+    assert_eq!(file.uid, Some(1000));
+    assert_eq!(file.owner_name, "zz85");
+    assert_eq!(file.owner_group, "staff");
+    assert_eq!(file.file_size, 21246462);
+    assert_eq!(file.permissions, "-rwsr-xr-x");
+    assert_eq!(file.mode & 0o7777, 0o4755);
+    assert_eq!(file.mode, 0o104755);
+
+    // Solaris' find has a type of its own for doors
+    let door = parse_find_printf(b"name_service_door\0D\0D\x00644\x000\x000\0root\0root\x0012\x001\x000\x001624694702.9\x001614814061.0\x001614814061.0\0\0");
+    assert_eq!(door[0].kind, FileKind::Socket);
+    assert_eq!(door[0].permissions, "Drw-r--r--");
+    assert_eq!(door[0].mode, S_IFDOOR | 0o644);
+}
+
+#[test]
+fn test_file_time() {
+    assert_eq!(
+        FileTime::parse("1624516764.2925405750"),
+        Some(FileTime {
+            secs: 1624516764,
+            nsecs: 292540575
+        })
+    );
+    assert_eq!(
+        FileTime::parse("12.5"),
+        Some(FileTime {
+            secs: 12,
+            nsecs: 500_000_000
+        })
+    );
+    assert_eq!(FileTime::parse("-1").unwrap().secs, -1);
+    assert_eq!(FileTime::parse("x"), None);
+    assert_eq!(
+        FileTime::from_nanos(-1),
+        FileTime {
+            secs: -1,
+            nsecs: 999_999_999
+        }
+    );
+    assert_eq!(
+        FileTime::from_nanos(1_500_000_000).to_system_time(),
+        UNIX_EPOCH + Duration::from_millis(1500)
+    );
+}
+
+#[test]
+fn test_recursive_list() {
+    let sample = "/srv/project/:
+total 8
+drwxr-xr-x 3 zz85 staff 4096 Jun 25 06:00 src
+-rw-r--r-- 1 zz85 staff  120 Jun 25 06:00 Cargo.toml
+
+/srv/project/src:
+total 4
+drwxr-xr-x 2 zz85 staff 4096 Jun 25 06:00 bin:
+-rw-r--r-- 1 zz85 staff  999 Jun 25 06:00 main.rs
+
+/srv/project/src/bin::
+total 0
+";
+
+    let sections = parse_recursive_list(sample, &ListFormat::utc());
+    let names = sections
+        .iter()
+        .map(|(dir, files)| (dir.as_str(), files.len()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        &[
+            ("/srv/project", 2),
+            ("/srv/project/src", 2),
+            ("/srv/project/src/bin:", 0)
+        ]
+    );
+    assert_eq!(sections[1].1[0].name, "bin:");
+
+    // without headers, eg. a directory without subdirectories on some ls
+    let sections = parse_recursive_list(
+        "total 0\n-rw-r--r-- 1 a b 1 Jun 25 06:00 f\n",
+        &ListFormat::utc(),
+    );
+    assert_eq!(sections.len(), 1);
+    assert_eq!(sections[0].0, "");
+}
+
+#[test]
+fn test_find_tree() {
+    let sample = b"/srv\0src\0d\0d\x00755\x001000\x001000\0zz85\0staff\x0012\x003\x004096\x001624694702.9\x001624694702.9\x001624694702.9\0\0\
+/srv/src\0main.rs\0f\0f\x00644\x001000\x001000\0zz85\0staff\x0013\x001\x00999\x001624694702.9\x001624694702.9\x001624694702.9\0\0";
+
+    let entries = parse_find_tree(sample);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].0, "/srv");
+    assert!(entries[0].1.kind.is_dir());
+    assert_eq!(entries[1].0, "/srv/src");
+    assert_eq!(entries[1].1.name, "main.rs");
+    assert_eq!(entries[1].1.file_size, 999);
 }
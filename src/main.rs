@@ -1,25 +1,56 @@
 use argh::FromArgs;
 use std::path::PathBuf;
+use std::time::Duration;
 
+mod askpass;
+mod checksum;
 mod cmd;
-use cmd::SshCmd;
+use cmd::{CmdRunner, Retry, SshCmd, Timeouts};
+mod coalesce;
+mod compress;
 mod display;
+mod error;
+mod helper;
+mod local;
 mod ls;
 mod mount;
+mod pool;
+mod process;
+mod session;
+mod sftp;
+mod shell;
 mod spinners;
+mod sshconfig;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod transport;
+
+use askpass::Askpass;
+use coalesce::Coalesced;
+use compress::{CompressMode, Compression};
+use helper::HelperRunner;
+use local::LocalRunner;
+use mount::FsOptions;
+use pool::Pool;
+use sftp::SftpRunner;
+use transport::{
+    parse_target, DockerTransport, KubectlTransport, ShellTransport, SshTransport, Target,
+};
 
 use display::RunnerWithSpinner;
 
 #[derive(FromArgs, Debug)]
 /// Fuse options
 struct FuseOption {
-    /// ssh user
+    /// ssh user, defaults to the one from ~/.ssh/config
     #[argh(option)]
-    pub user: String,
+    pub user: Option<String>,
 
-    /// ssh target host
+    /// ssh target host, a host alias from ~/.ssh/config, or a URL like
+    /// docker://container/path, k8s://namespace/pod/path or sh:///path
     #[argh(option)]
-    pub target: String,
+    pub target: Option<String>,
 
     /// ssh options
     #[argh(option)]
@@ -32,22 +63,254 @@ struct FuseOption {
     /// display spinners
     #[argh(option)]
     pub spinner: Option<bool>,
+
+    /// talk to the sftp subsystem instead of running ls and cat
+    #[argh(switch)]
+    pub sftp: bool,
+
+    /// run a helper agent on the remote host (needs python3), falling
+    /// back to ls and cat when it can't be started
+    #[argh(switch)]
+    pub helper: bool,
+
+    /// serve a local directory instead of a ssh host
+    #[argh(option)]
+    pub local: Option<PathBuf>,
+
+    /// milliseconds of latency added to each call to the local directory
+    #[argh(option)]
+    pub latency: Option<u64>,
+
+    /// seconds to wait for the ssh connection to be established (default 10)
+    #[argh(option)]
+    pub connect_timeout: Option<u64>,
+
+    /// seconds a directory listing may take (default 30)
+    #[argh(option)]
+    pub list_timeout: Option<u64>,
+
+    /// seconds a file read may take (default 60)
+    #[argh(option)]
+    pub read_timeout: Option<u64>,
+
+    /// times a command is retried when the connection fails (default 3)
+    #[argh(option)]
+    pub retries: Option<u32>,
+
+    /// most remote commands running at once (default 4)
+    #[argh(option)]
+    pub max_sessions: Option<usize>,
+
+    /// command printing the ssh password (the prompt is in $SSHFUSE_PROMPT),
+    /// instead of asking on the terminal
+    #[argh(option)]
+    pub askpass_cmd: Option<String>,
+
+    /// compress file contents on the way: off (default), auto, gzip or zstd
+    #[argh(option)]
+    pub compress: Option<CompressMode>,
+
+    /// size below which a file isn't compressed (default 65536)
+    #[argh(option)]
+    pub compress_min: Option<u64>,
+
+    /// check file contents against a remote checksum and their listed
+    /// size, fetching again on mismatch
+    #[argh(switch)]
+    pub verify: bool,
+
+    /// levels of subdirectories listed in the background whenever a
+    /// directory is (default 0, off)
+    #[argh(option)]
+    pub prefetch_depth: Option<u32>,
+
+    /// most entries listed by one prefetch (default 2000)
+    #[argh(option)]
+    pub prefetch_max: Option<usize>,
 }
 
 fn main() {
+    // ssh runs us as its SSH_ASKPASS program
+    if let Some(socket) = std::env::var_os(askpass::SOCKET_ENV) {
+        let prompt = std::env::args().nth(1).unwrap_or_default();
+        std::process::exit(askpass::client(socket.as_ref(), &prompt));
+    }
+
     let args = argh::from_env::<FuseOption>();
     println!("{:?}", args);
+    if let Err(e) = check_combinations(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let defaults = Timeouts::default();
+    let secs_or = |secs: Option<u64>, default| secs.map(Duration::from_secs).unwrap_or(default);
+    let timeouts = Timeouts {
+        connect: secs_or(args.connect_timeout, defaults.connect),
+        list: secs_or(args.list_timeout, defaults.list),
+        read: secs_or(args.read_timeout, defaults.read),
+    };
 
-    let user = args.user;
-    let target = args.target;
+    let mount_options = MountOptions {
+        spinner: args.spinner.unwrap_or(true),
+        max_sessions: args.max_sessions.unwrap_or(4),
+        timeouts,
+        fs: FsOptions {
+            verify: args.verify,
+            prefetch_depth: args.prefetch_depth.unwrap_or(0),
+            prefetch_max: args.prefetch_max.unwrap_or(2000),
+        },
+    };
+
+    if let Some(root) = args.local {
+        let latency = Duration::from_millis(args.latency.unwrap_or(0));
+        let fsname = root.display().to_string();
+        return mount_with(
+            LocalRunner::new(root).with_latency(latency),
+            &fsname,
+            &mount_options,
+        );
+    }
+
+    let target = required(args.target, "--target");
     let options = args.options.unwrap_or_default();
 
-    let cmd_runner = SshCmd::new(&user, &target, &options);
-    let spinner_runner = RunnerWithSpinner::new(&user, &target, &options);
+    let retry = Retry {
+        retries: args.retries.unwrap_or(Retry::default().retries),
+        ..Default::default()
+    };
+
+    let compression = Compression {
+        mode: args.compress.unwrap_or(Compression::default().mode),
+        min_size: args.compress_min.unwrap_or(Compression::default().min_size),
+    };
+
+    let (target, root) = parse_target(&target).unwrap_or_else(|e| {
+        eprintln!("Invalid --target: {}", e);
+        std::process::exit(1)
+    });
+
+    let cmd_runner = match target {
+        Target::Ssh(host) => {
+            let mut ssh = SshTransport::new(args.user.as_deref(), &host, &options)
+                .with_connect_timeout(timeouts.connect);
+
+            match Askpass::start(args.askpass_cmd) {
+                Ok(askpass) => ssh = ssh.with_askpass(askpass),
+                Err(e) => println!("Can't answer ssh prompts: {}", e),
+            }
+
+            match ssh.resolve_config() {
+                Ok(config) => println!("Connecting to {} ({})", host, config),
+                Err(e) => println!("Can't resolve ssh settings for {}: {}", host, e),
+            }
+
+            if args.sftp {
+                match SftpRunner::spawn(ssh.subsystem_command("sftp")) {
+                    Ok(sftp_runner) => {
+                        let sftp_runner = sftp_runner.with_timeouts(timeouts);
+                        return mount_with(sftp_runner, &ssh.destination(), &mount_options);
+                    }
+                    Err(e) => println!("sftp unavailable ({}), using ls and cat", e),
+                }
+            }
+
+            SshCmd::new(ssh)
+        }
+        Target::Docker(container) => SshCmd::new(DockerTransport::new(&container)),
+        Target::Kubectl { namespace, pod } => SshCmd::new(KubectlTransport::new(&namespace, &pod)),
+        Target::Shell => SshCmd::new(ShellTransport),
+    }
+    .with_root(&root)
+    .with_timeouts(timeouts)
+    .with_retry(retry)
+    .with_compression(compression)
+    .with_verify(args.verify);
+
+    let fsname = cmd_runner.name();
+    cmd_runner.detect_list_format();
+
+    if args.helper {
+        match HelperRunner::connect(&cmd_runner) {
+            Ok(helper_runner) => mount_with(helper_runner, &fsname, &mount_options),
+            Err(e) => {
+                println!("Helper unavailable ({}), using ls and cat", e);
+                mount_with(cmd_runner, &fsname, &mount_options)
+            }
+        }
+    } else {
+        mount_with(cmd_runner, &fsname, &mount_options)
+    }
+}
+
+/// options that would be ignored with the others given
+fn check_combinations(args: &FuseOption) -> Result<(), String> {
+    if args.local.is_some() {
+        let remote = [
+            (args.target.is_some(), "--target"),
+            (args.sftp, "--sftp"),
+            (args.helper, "--helper"),
+        ];
+        return match remote.iter().find(|(given, _)| *given) {
+            Some((_, name)) => Err(format!("{} can't be used with --local", name)),
+            None => Ok(()),
+        };
+    }
+
+    if args.sftp && args.helper {
+        return Err("--sftp and --helper can't be used together".into());
+    }
+    // other targets have no sftp subsystem to talk to
+    let target = args.target.as_deref().map(parse_target);
+    if args.sftp && !matches!(target, None | Some(Err(_)) | Some(Ok((Target::Ssh(_), _)))) {
+        return Err("--sftp needs a ssh --target".into());
+    }
+
+    Ok(())
+}
+
+fn required(value: Option<String>, name: &str) -> String {
+    value.unwrap_or_else(|| {
+        eprintln!("Required option {} not provided.", name);
+        std::process::exit(1)
+    })
+}
+
+/// how the runner is wrapped and mounted, whatever it is
+struct MountOptions {
+    spinner: bool,
+    max_sessions: usize,
+    /// also bounds how long a command waits for its turn in the pool
+    timeouts: Timeouts,
+    fs: FsOptions,
+}
+
+fn mount_with(runner: impl CmdRunner + 'static, fsname: &str, options: &MountOptions) {
+    // duplicates are caught before they take a slot in the pool
+    let pool = Pool::new(runner, options.max_sessions).with_timeouts(options.timeouts);
+    let runner = Coalesced::new(pool);
 
-    if args.spinner.unwrap_or(true) {
-        mount::mount(spinner_runner)
+    if options.spinner {
+        mount::mount(RunnerWithSpinner::new(runner), fsname, options.fs)
     } else {
-        mount::mount(cmd_runner)
+        mount::mount(runner, fsname, options.fs)
     }
 }
+
+#[test]
+fn test_check_combinations() {
+    let check = |args: &[&str]| {
+        let args = FuseOption::from_args(&["sshfuse"], args).unwrap();
+        check_combinations(&args)
+    };
+
+    assert!(check(&["--target", "host", "--sftp"]).is_ok());
+    assert!(check(&["--target", "docker://web/srv", "--helper"]).is_ok());
+    assert!(check(&["--local", "/srv", "--latency", "10"]).is_ok());
+
+    assert!(check(&["--target", "docker://web/srv", "--sftp"]).is_err());
+    assert!(check(&["--target", "sh:///srv", "--sftp"]).is_err());
+    assert!(check(&["--target", "host", "--sftp", "--helper"]).is_err());
+    assert!(check(&["--local", "/srv", "--helper"]).is_err());
+    assert!(check(&["--local", "/srv", "--target", "host"]).is_err());
+}
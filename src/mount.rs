@@ -1,12 +1,20 @@
-use crate::cmd::CmdRunner;
-use crate::ls::FileMeta;
+use crate::cmd::{CmdRunner, Listings};
+use crate::error::RunnerError;
+use crate::ls::{
+    FileKind, FileMeta, FileTime, S_IFBLK, S_IFCHR, S_IFDIR, S_IFDOOR, S_IFIFO, S_IFLNK, S_IFMT,
+    S_IFPORT, S_IFREG, S_IFSOCK, S_IFWHT,
+};
+use crate::pool::{self, Priority};
+use crate::process;
+use crate::stats::STATS;
 use fuse_mt::*;
 use libc;
 use std::ffi::OsString;
 use std::path::Path;
 use std::process::Command;
 use std::str;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 use std::time::SystemTime;
 use std::{collections::HashMap, path::PathBuf};
@@ -17,13 +25,39 @@ use std::{
 };
 
 const TTL: Duration = Duration::from_secs(60);
+/// files are fetched and cached in aligned blocks of this size
+const BLOCK_SIZE: u64 = 128 * 1024;
+/// upper bound of the block cache (256MB)
+const MAX_CACHED_BLOCKS: usize = 2048;
+/// fetches after the first one of a range whose length is off
+const MAX_VERIFY_ATTEMPTS: u32 = 2;
+
+/// what the filesystem does besides answering requests
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsOptions {
+    /// reads that don't match the size listed are fetched again, then fail
+    /// with EIO
+    pub verify: bool,
+    /// levels of subdirectories listed in the background when a directory
+    /// is, 0 to only list what's asked for
+    pub prefetch_depth: u32,
+    /// most entries listed by one prefetch
+    pub prefetch_max: usize,
+}
 
-/// helper to mount a path
-pub fn mount(runner: impl CmdRunner + 'static) {
+/// helper to mount a path. `fsname` is what the mount shows up as in
+/// `mount` and `df`, eg. the ssh destination
+pub fn mount(runner: impl CmdRunner + 'static, fsname: &str, options: FsOptions) {
+    // commas separate mount options
+    let fsname = format!("fsname={}", fsname.replace(',', "\\,"));
     let fuse_args: Vec<&OsStr> = vec![
         &OsStr::new("-o"),
         &OsStr::new("auto_unmount"),
         &OsStr::new("ro"),
+        &OsStr::new("-o"),
+        &OsStr::new(&fsname),
+        &OsStr::new("-o"),
+        &OsStr::new("subtype=sshfuse"),
     ];
 
     let mount_point = format!("/tmp/test");
@@ -38,7 +72,7 @@ pub fn mount(runner: impl CmdRunner + 'static) {
         println!("umount {:?}", e);
     });
 
-    let filesystem = SshFuseFs::new(runner);
+    let filesystem = SshFuseFs::new(runner).with_options(options);
 
     fuse_mt::mount(
         fuse_mt::FuseMT::new(filesystem, 10),
@@ -48,11 +82,39 @@ pub fn mount(runner: impl CmdRunner + 'static) {
     .unwrap();
 }
 
+/// the uid and gid of the owner of `meta` on the remote, shown as they are
+/// like sshfs does. listings from `ls -l` only have names, which are
+/// shown as the mounting user's unless they're numbers
+fn owner_ids(meta: &FileMeta) -> (u32, u32) {
+    let uid = meta.uid.or_else(|| meta.owner_name.parse().ok());
+    let gid = meta.gid.or_else(|| meta.owner_group.parse().ok());
+    (
+        uid.unwrap_or_else(|| unsafe { libc::getuid() }),
+        gid.unwrap_or_else(|| unsafe { libc::getgid() }),
+    )
+}
+
+/// what the kernel is told `kind` is
+fn file_type(mode: u32) -> FileType {
+    match mode & S_IFMT {
+        S_IFDIR => FileType::Directory,
+        S_IFLNK => FileType::Symlink,
+        S_IFIFO => FileType::NamedPipe,
+        S_IFCHR => FileType::CharDevice,
+        S_IFBLK => FileType::BlockDevice,
+        // Solaris doors and event ports (or BSD whiteouts) can't be read
+        // either, which the kernel only asks us to for regular files
+        S_IFSOCK | S_IFDOOR | S_IFPORT | S_IFWHT => FileType::Socket,
+        _ => FileType::RegularFile,
+    }
+}
+
 #[derive(Debug)]
 struct CachedMeta {
     file_meta: Option<FileMeta>,
-    directory: bool,
-    perms: u16,
+    kind: FileKind,
+    /// the whole `st_mode`, with all the permissions until listed
+    mode: u32,
     size: u64,
     children: Option<Vec<String>>,
     updated: bool,
@@ -63,8 +125,8 @@ impl Default for CachedMeta {
     fn default() -> Self {
         Self {
             file_meta: Default::default(),
-            directory: Default::default(),
-            perms: 0o7777,
+            kind: Default::default(),
+            mode: S_IFREG | 0o7777,
             size: Default::default(),
             children: Default::default(),
             updated: Default::default(),
@@ -73,9 +135,109 @@ impl Default for CachedMeta {
     }
 }
 
-struct CachedFile {
-    contents: Vec<u8>,
+impl CachedMeta {
+    /// an entry for `meta`. if it's a directory, its children need
+    /// another fetch
+    fn new(meta: FileMeta) -> Self {
+        Self {
+            kind: meta.kind.clone(),
+            size: meta.file_size as u64,
+            mode: meta.mode,
+            file_meta: Some(meta),
+            ..Default::default()
+        }
+    }
+}
+
+struct CachedBlock {
+    /// shorter than BLOCK_SIZE for the last block of a file
+    data: Vec<u8>,
     last_updated: Instant,
+    last_used: Instant,
+}
+
+/// A background thread prefetching directory trees, one at a time and
+/// behind interactive requests
+struct Prefetcher {
+    queue: Mutex<mpsc::Sender<String>>,
+    /// cache keys of the directories queued or being prefetched
+    pending: Arc<Mutex<Vec<String>>>,
+}
+
+impl Prefetcher {
+    /// starts the thread, which stops once the queue is dropped
+    fn spawn<T: CmdRunner + 'static>(
+        runner: Arc<T>,
+        cache: Arc<Mutex<HashMap<String, CachedMeta>>>,
+        options: FsOptions,
+    ) -> Self {
+        let (queue, keys) = mpsc::channel::<String>();
+        let pending: Arc<Mutex<Vec<String>>> = Default::default();
+
+        {
+            let pending = pending.clone();
+            thread::spawn(move || {
+                for key in keys {
+                    // eg. a prefetch ahead of it in the queue got there
+                    if !subtree_fresh(&cache.lock().unwrap(), &key, options.prefetch_depth) {
+                        let root = if key.is_empty() { "/" } else { &key }.to_string();
+                        // `key` itself was just listed, the tree goes one level further
+                        let tree = pool::with_priority(Priority::Background, || {
+                            runner.fetch_tree(
+                                &root,
+                                options.prefetch_depth + 1,
+                                options.prefetch_max,
+                            )
+                        });
+                        SshFuseFs::<T>::populate_tree_cache(&cache, &root, tree);
+                    }
+
+                    let mut pending = pending.lock().unwrap();
+                    if let Some(i) = pending.iter().position(|k| *k == key) {
+                        pending.remove(i);
+                    }
+                }
+            });
+        }
+
+        Self {
+            queue: Mutex::new(queue),
+            pending,
+        }
+    }
+}
+
+/// how many levels cache key `key` is below `ancestor`, if it's under it
+fn levels_below(ancestor: &str, key: &str) -> Option<u32> {
+    if key == ancestor {
+        return Some(0);
+    }
+    let rest = key.strip_prefix(ancestor)?.strip_prefix('/')?;
+    Some(rest.split('/').count() as u32)
+}
+
+/// whether the directories `levels` levels under cache key `key` were all
+/// listed within the TTL
+fn subtree_fresh(cache: &HashMap<String, CachedMeta>, key: &str, levels: u32) -> bool {
+    if levels == 0 {
+        return true;
+    }
+    let children = match cache.get(key).and_then(|m| m.children.as_ref()) {
+        Some(children) => children,
+        None => return false,
+    };
+
+    children.iter().all(|name| {
+        let child = format!("{}/{}", key, name);
+        match cache.get(&child) {
+            Some(meta) if meta.kind.is_dir() => {
+                meta.updated
+                    && meta.last_updated.elapsed() < TTL
+                    && subtree_fresh(cache, &child, levels - 1)
+            }
+            _ => true,
+        }
+    })
 }
 
 /// this is a file system back by a cache built on the fly from a remote
@@ -83,29 +245,46 @@ struct CachedFile {
 /// would not have complete data. Ideally this could be merged from stat
 /// information
 struct SshFuseFs<T> {
-    runner: T,
+    runner: Arc<T>,
     /// filesystem metadata cache
     cache: Arc<Mutex<HashMap<String, CachedMeta>>>,
-    /// file cache
-    file_cache: Arc<Mutex<HashMap<String, CachedFile>>>,
+    /// file contents cache, keyed by path and block index
+    block_cache: Arc<Mutex<HashMap<(String, u64), CachedBlock>>>,
+    options: FsOptions,
+    /// with a prefetch depth, where directories to prefetch are sent
+    prefetcher: Option<Prefetcher>,
 
     counter: AtomicU32,
 }
 
-impl<T: CmdRunner + Sync + Send> SshFuseFs<T> {
+impl<T: CmdRunner + Sync + Send + 'static> SshFuseFs<T> {
     fn new(runner: T) -> Self {
         // let trace_bar = get_progress_bar(&views);
 
         SshFuseFs {
-            runner,
+            runner: Arc::new(runner),
             cache: Default::default(),
-            file_cache: Default::default(),
+            block_cache: Default::default(),
+            options: Default::default(),
+            prefetcher: None,
 
             // trace_bar,
             counter: Default::default(),
         }
     }
 
+    fn with_options(mut self, options: FsOptions) -> Self {
+        self.options = options;
+        if options.prefetch_depth > 0 {
+            self.prefetcher = Some(Prefetcher::spawn(
+                self.runner.clone(),
+                self.cache.clone(),
+                options,
+            ));
+        }
+        self
+    }
+
     fn get_key(key: &str) -> &str {
         // keys are stored without trailing slashes
         let key = if key == "/" { "" } else { key };
@@ -118,7 +297,7 @@ impl<T: CmdRunner + Sync + Send> SshFuseFs<T> {
     /// based on a key path, check the cache,
     /// otherwise fetch a file/directory metadata
     /// used by getattr and opendir
-    fn get_or_update_metadata(&self, path_str: &str) {
+    fn get_or_update_metadata(&self, path_str: &str) -> Result<(), RunnerError> {
         let mut buf = PathBuf::from(path_str);
         buf.pop();
         let parent_path = buf.to_str().unwrap();
@@ -137,12 +316,53 @@ impl<T: CmdRunner + Sync + Send> SshFuseFs<T> {
             }
         };
 
-        if !in_cache {
-            self.update_dir_cache(parent_path, parent_path);
+        if in_cache {
+            return Ok(());
+        }
+
+        // the root has no parent to be listed in
+        if path_str == parent_path {
+            self.update_dir_cache(parent_path, parent_path)
+        } else {
+            self.update_meta_cache(path_str)
         }
     }
 
-    fn fetch_path(&self, path: &str) -> Option<Vec<FileMeta>> {
+    /// fetches the metadata of `path` alone, without listing its parent
+    fn update_meta_cache(&self, path: &str) -> Result<(), RunnerError> {
+        let meta = match self.runner.fetch_meta(path) {
+            Ok(meta) => meta,
+            // can't stat, the parent's listing has it along with its siblings
+            Err(RunnerError::CommandMissing(_)) => return self.update_parent_cache(path),
+            Err(e) => {
+                println!("Error: {} {}", path, e);
+                return Err(e);
+            }
+        };
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(Self::get_key(path).into(), CachedMeta::new(meta));
+        Ok(())
+    }
+
+    /// lists the parent of `path` into the cache, `NotFound` when `path`
+    /// isn't in it
+    fn update_parent_cache(&self, path: &str) -> Result<(), RunnerError> {
+        let mut parent = PathBuf::from(path);
+        parent.pop();
+        let parent = parent.to_str().unwrap();
+        self.update_dir_cache(&format!("{}/", parent.trim_end_matches('/')), parent)?;
+
+        if self.cache.lock().unwrap().contains_key(Self::get_key(path)) {
+            Ok(())
+        } else {
+            Err(RunnerError::NotFound(path.into()))
+        }
+    }
+
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
         self.runner.fetch_path(path)
     }
 
@@ -150,16 +370,54 @@ impl<T: CmdRunner + Sync + Send> SshFuseFs<T> {
     /// the path without slash is used to populate the cache's keys
     /// path with the forward slash is to force `ls` to list the directory
     /// content and not just the path
-    fn update_dir_cache(&self, path: &str, no_trailing_key: &str) {
+    fn update_dir_cache(&self, path: &str, no_trailing_key: &str) -> Result<(), RunnerError> {
         // for root "/", the key is ""
         let no_trailing_key = if path == "/" { "" } else { no_trailing_key };
 
-        let meta = match self.fetch_path(path) {
-            Some(meta) => meta,
-            _ => return,
-        };
+        let meta = self.fetch_path(path).map_err(|e| {
+            println!("Error: {} {}", path, e);
+            e
+        })?;
 
-        let mut cache = self.cache.lock();
+        Self::populate_dir_cache(&self.cache, no_trailing_key, meta);
+        Ok(())
+    }
+
+    /// lists the directories at cache keys `keys` together, in one round
+    /// trip where the runner can. results are in the order of `keys`
+    fn update_dir_caches(&self, keys: &[String]) -> Vec<Result<(), RunnerError>> {
+        let paths = keys.iter().map(|k| format!("{}/", k)).collect::<Vec<_>>();
+        let mut listings = self
+            .runner
+            .fetch_paths(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+
+        // in order, a parent's listing resets its children
+        keys.iter()
+            .zip(&paths)
+            .map(|(key, path)| {
+                let listing = listings
+                    .remove(path)
+                    .unwrap_or_else(|| Err(RunnerError::Parse(format!("{} not listed", path))));
+                match listing {
+                    Ok(meta) => {
+                        Self::populate_dir_cache(&self.cache, key, meta);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        println!("Error: {} {}", path, e);
+                        Err(e)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn populate_dir_cache(
+        cache: &Mutex<HashMap<String, CachedMeta>>,
+        no_trailing_key: &str,
+        meta: Vec<FileMeta>,
+    ) {
+        let mut cache = cache.lock();
         let cache = cache.as_mut().unwrap();
 
         // populate cache
@@ -169,32 +427,89 @@ impl<T: CmdRunner + Sync + Send> SshFuseFs<T> {
 
         parent.updated = true;
         parent.children = Some(children);
-        parent.directory = true;
+        parent.kind = FileKind::Directory;
+        parent.mode = S_IFDIR | parent.mode & 0o7777;
+        parent.last_updated = Instant::now();
 
-        // update children
+        // update children, keeping the listings of those still directories
         for m in meta {
             let child_key = format!("{}/{}", no_trailing_key, m.name);
-            cache.insert(
-                child_key,
-                CachedMeta {
-                    file_meta: Some(m.clone()),
-                    directory: m.directory,
-                    size: m.file_size as u64,
-                    perms: m.perms,
-                    children: None,
-                    updated: false, // this means that if it's a directory, children of this directory needs another fetch
-                    last_updated: Instant::now(),
-                },
-            );
+            let mut child = CachedMeta::new(m);
+            if let Some(old) = cache.get_mut(&child_key) {
+                if old.kind.is_dir() && child.kind.is_dir() {
+                    child.updated = old.updated;
+                    child.children = old.children.take();
+                    child.last_updated = old.last_updated;
+                }
+            }
+            cache.insert(child_key, child);
         }
 
         // println!("Cache {:#?}", cache);
     }
 
+    /// lists the directories under `key`, `prefetch_depth` levels deep, in
+    /// the background so browsing into them is answered from the cache.
+    /// nothing is queued when they were listed recently, or will be by a
+    /// prefetch already queued
+    fn prefetch(&self, key: &str) {
+        let prefetcher = match &self.prefetcher {
+            Some(prefetcher) => prefetcher,
+            None => return,
+        };
+        let depth = self.options.prefetch_depth;
+
+        let mut pending = prefetcher.pending.lock().unwrap();
+        let covered = pending
+            .iter()
+            .any(|root| matches!(levels_below(root, key), Some(levels) if levels <= depth));
+        if covered || subtree_fresh(&self.cache.lock().unwrap(), key, depth) {
+            return;
+        }
+
+        pending.push(key.to_string());
+        let _ = prefetcher.queue.lock().unwrap().send(key.to_string());
+    }
+
+    /// fills the cache with the listings of `tree`, but `root`'s
+    fn populate_tree_cache(cache: &Mutex<HashMap<String, CachedMeta>>, root: &str, tree: Listings) {
+        // parents first, so each directory's entry exists when it's listed
+        let mut dirs = tree
+            .into_iter()
+            .filter(|(dir, _)| *dir != root)
+            .filter_map(|(dir, listing)| Some((dir, listing.ok()?)))
+            .collect::<Vec<_>>();
+        dirs.sort_by_key(|(dir, _)| dir.matches('/').count());
+
+        for (dir, meta) in dirs {
+            Self::populate_dir_cache(cache, Self::get_key(&dir), meta);
+        }
+    }
+
+    /// subdirectories of `key` that were listed before, but not recently
+    fn stale_subdirs(&self, key: &str) -> Vec<String> {
+        let cache = self.cache.lock().unwrap();
+        let children = match cache.get(key).and_then(|m| m.children.as_ref()) {
+            Some(children) => children,
+            None => return vec![],
+        };
+
+        children
+            .iter()
+            .map(|name| format!("{}/{}", key, name))
+            .filter(|child| match cache.get(child) {
+                Some(meta) => {
+                    meta.kind.is_dir() && meta.updated && meta.last_updated.elapsed() > TTL
+                }
+                None => false,
+            })
+            .collect()
+    }
+
     /// attempts to get directory listing from cache, other make a fetch
     /// to populate cache.
     /// this is used by readdir
-    fn get_dir_list_from_cache(&self, path: &str) -> Vec<DirectoryEntry> {
+    fn get_dir_list_from_cache(&self, path: &str) -> Result<Vec<DirectoryEntry>, RunnerError> {
         let dir_path = if path.ends_with("/") {
             path.to_string()
         } else {
@@ -213,7 +528,12 @@ impl<T: CmdRunner + Sync + Send> SshFuseFs<T> {
         };
 
         if require_update {
-            self.update_dir_cache(&dir_path, &no_trailing_key);
+            // subdirectories that were browsed are likely to be again, eg.
+            // by `tree`, so they're refreshed along in the same round trip
+            let mut keys = vec![no_trailing_key.clone()];
+            keys.extend(self.stale_subdirs(&no_trailing_key));
+            self.update_dir_caches(&keys).remove(0)?;
+            self.prefetch(&no_trailing_key);
         }
 
         let mut entries: Vec<DirectoryEntry> = vec![];
@@ -231,25 +551,158 @@ impl<T: CmdRunner + Sync + Send> SshFuseFs<T> {
                     .get(&format!("{}/{}", no_trailing_key, filename))
                     .unwrap();
 
-                let kind = if child.directory {
-                    FileType::Directory
-                } else {
-                    FileType::RegularFile
-                };
-
-                entries.push(DirectoryEntry { name, kind })
+                entries.push(DirectoryEntry {
+                    name,
+                    kind: file_type(child.mode),
+                })
             }
         }
 
-        entries
+        Ok(entries)
     }
 
-    fn get_entries(&self, path: &Path) -> Vec<DirectoryEntry> {
+    fn get_entries(&self, path: &Path) -> Result<Vec<DirectoryEntry>, RunnerError> {
         let path = path.to_str().unwrap();
 
         self.get_dir_list_from_cache(path)
     }
 
+    /// reads `size` bytes at `offset`, fetching only the blocks that aren't
+    /// cached yet. runs of missing blocks are fetched with a single range
+    /// request each
+    fn read_blocks(&self, path: &str, offset: u64, size: u32) -> Result<Vec<u8>, RunnerError> {
+        if size == 0 {
+            return Ok(vec![]);
+        }
+
+        let first = offset / BLOCK_SIZE;
+        let last = (offset + size as u64 - 1) / BLOCK_SIZE;
+
+        let missing = {
+            let cache = self.block_cache.lock().unwrap();
+            (first..=last)
+                .filter(|i| match cache.get(&(path.to_string(), *i)) {
+                    Some(block) => block.last_updated.elapsed() > TTL,
+                    None => true,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // group consecutive block indexes into runs
+        let mut runs: Vec<(u64, u64)> = vec![];
+        for i in missing {
+            match runs.last_mut() {
+                Some((start, count)) if *start + *count == i => *count += 1,
+                _ => runs.push((i, 1)),
+            }
+        }
+
+        for (start, count) in runs {
+            let data = self.fetch_range(path, start * BLOCK_SIZE, count * BLOCK_SIZE)?;
+
+            let mut cache = self.block_cache.lock().unwrap();
+            for (n, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+                Self::insert_block(&mut cache, (path.to_string(), start + n as u64), chunk);
+            }
+        }
+
+        let mut cache = self.block_cache.lock().unwrap();
+        let mut contents = vec![];
+        for i in first..=last {
+            match cache.get_mut(&(path.to_string(), i)) {
+                Some(block) => {
+                    block.last_used = Instant::now();
+                    contents.extend_from_slice(&block.data);
+                    if (block.data.len() as u64) < BLOCK_SIZE {
+                        break;
+                    }
+                }
+                // past the end of the file
+                None => break,
+            }
+        }
+
+        let start = (offset - first * BLOCK_SIZE) as usize;
+        let end = (start + size as usize).min(contents.len());
+
+        Ok(contents.get(start..end).unwrap_or_default().to_vec())
+    }
+
+    /// fetches a range of `path`, the whole file at once when the range
+    /// covers it. when verifying, the length has to agree with the size
+    /// listed for the file: after a mismatch the listing is refreshed, in
+    /// case the file changed, and the range fetched again
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        let mut attempt = 0;
+        loop {
+            let size = {
+                let cache = self.cache.lock().unwrap();
+                match cache.get(Self::get_key(path)) {
+                    Some(meta) if meta.kind == FileKind::File => Some(meta.size),
+                    _ => None,
+                }
+            };
+
+            let data = match size {
+                Some(size) if offset == 0 && size <= len => self.runner.fetch_file(path)?,
+                _ => self.runner.fetch_range(path, offset, len)?,
+            };
+            let size = match size {
+                Some(size) if self.options.verify => size,
+                // nothing to compare with
+                _ => return Ok(data),
+            };
+            let expected = size.saturating_sub(offset).min(len);
+            if data.len() as u64 == expected {
+                return Ok(data);
+            }
+
+            let e = RunnerError::Corrupted(format!(
+                "{} bytes of {} @{}, expected {}",
+                data.len(),
+                path,
+                offset,
+                expected
+            ));
+            if attempt >= MAX_VERIFY_ATTEMPTS {
+                return Err(e);
+            }
+            println!("{}, fetching again", e);
+
+            let mut parent = PathBuf::from(path);
+            parent.pop();
+            let parent = parent.to_str().unwrap();
+            self.update_dir_cache(parent, parent)?;
+            attempt += 1;
+        }
+    }
+
+    fn insert_block(
+        cache: &mut HashMap<(String, u64), CachedBlock>,
+        key: (String, u64),
+        data: &[u8],
+    ) {
+        if cache.len() >= MAX_CACHED_BLOCKS && !cache.contains_key(&key) {
+            // evict the least recently used block
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, block)| block.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+
+        cache.insert(
+            key,
+            CachedBlock {
+                data: data.to_vec(),
+                last_updated: Instant::now(),
+                last_used: Instant::now(),
+            },
+        );
+    }
+
     /// use this for tracking or logging syscalls
     fn track(&self, syscall: &str, path: &Path) {
         let count = self.counter.fetch_add(1, Ordering::Relaxed);
@@ -260,7 +713,7 @@ impl<T: CmdRunner + Sync + Send> SshFuseFs<T> {
     }
 }
 
-impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
+impl<T: CmdRunner + 'static> FilesystemMT for SshFuseFs<T> {
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
         self.track("init", &Path::new(""));
         Ok(())
@@ -268,53 +721,56 @@ impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
 
     fn destroy(&self, _req: RequestInfo) {
         self.track("destroy", &Path::new(""));
-        // Nothing.
+        // don't keep unmounting waiting on a stalled host
+        process::cancel_all();
+        println!("{}", STATS.summary());
     }
 
-    fn getattr(&self, _req: RequestInfo, path: &std::path::Path, _fh: Option<u64>) -> ResultEntry {
+    fn getattr(&self, req: RequestInfo, path: &std::path::Path, _fh: Option<u64>) -> ResultEntry {
         self.track("getattr", path);
 
         let path_str = path.to_str().unwrap();
-        self.get_or_update_metadata(path_str);
+        process::on_behalf_of(req.pid, || self.get_or_update_metadata(path_str))
+            .map_err(|e| e.errno())?;
 
         // TODO refresh as a background thread after x interval
         let cache = self.cache.lock().unwrap();
-        let (kind, perms, size, seconds) = match cache.get(Self::get_key(path_str)) {
-            Some(meta) => {
-                let kind = if meta.directory {
-                    FileType::Directory
-                } else {
-                    FileType::RegularFile
-                };
-
-                let seconds = match &meta.file_meta {
-                    Some(f) => f.modified_since,
-                    None => 0,
-                };
-
-                (kind, meta.perms, meta.size, seconds)
-            }
+        let (kind, mode, size, meta) = match cache.get(Self::get_key(path_str)) {
+            Some(meta) => (
+                meta.kind.clone(),
+                meta.mode,
+                meta.size,
+                meta.file_meta.clone(),
+            ),
             _ => {
                 // println!("Not found {}\n{:?}", path_str, cache);
-                return Err(libc::ENOSYS);
+                return Err(libc::ENOENT);
             }
         };
 
-        let time = |secs: u32| SystemTime::UNIX_EPOCH + Duration::new(secs as u64, 0);
+        let meta = meta.unwrap_or_default();
+        let (uid, gid) = owner_ids(&meta);
+        let mtime = meta.modified;
+        // listings from `ls -l` only know the modification time
+        let atime = meta.accessed.unwrap_or(mtime);
+        let ctime = meta.changed.unwrap_or(mtime);
 
         let attr = FileAttr {
             size,
-            blocks: 4096 as u64,
-            atime: time(seconds),
-            mtime: time(seconds),
-            ctime: time(seconds),
-            crtime: SystemTime::UNIX_EPOCH,
-            kind,
-            perm: perms,
-            nlink: 1,
-            uid: 1,
-            gid: 1,
-            rdev: 0,
+            blocks: meta.blocks.unwrap_or(4096),
+            atime: atime.to_system_time(),
+            mtime: mtime.to_system_time(),
+            ctime: ctime.to_system_time(),
+            crtime: meta
+                .born
+                .map(FileTime::to_system_time)
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+            kind: file_type(mode),
+            perm: (mode & 0o7777) as u16,
+            nlink: meta.links.max(1) as u32,
+            uid,
+            gid,
+            rdev: kind.rdev() as u32,
             flags: 0,
         };
 
@@ -381,9 +837,19 @@ impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
         Err(libc::ENOSYS)
     }
 
-    fn readlink(&self, _req: RequestInfo, path: &std::path::Path) -> ResultData {
+    fn readlink(&self, req: RequestInfo, path: &std::path::Path) -> ResultData {
         self.track("readlink", path);
-        Err(libc::ENOSYS)
+
+        let path_str = path.to_str().unwrap();
+        process::on_behalf_of(req.pid, || self.get_or_update_metadata(path_str))
+            .map_err(|e| e.errno())?;
+
+        let cache = self.cache.lock().unwrap();
+        match cache.get(Self::get_key(path_str)).map(|m| &m.kind) {
+            Some(FileKind::Symlink(target)) => Ok(target.clone().into_bytes()),
+            Some(_) => Err(libc::EINVAL),
+            None => Err(libc::ENOENT),
+        }
     }
 
     fn mknod(
@@ -455,24 +921,8 @@ impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
 
     fn open(&self, _req: RequestInfo, path: &std::path::Path, _flags: u32) -> ResultOpen {
         self.track("open", path);
-        let path = path.to_str().unwrap();
 
-        let mut cache = self.file_cache.lock().unwrap();
-        if cache.contains_key(path) {
-            return Ok((1, 1));
-        }
-        let output = self.runner.fetch_file(path);
-
-        if output.stderr.len() > 0 {
-            return Err(libc::ENOSYS);
-        }
-
-        let file = CachedFile {
-            contents: output.stdout,
-            last_updated: Instant::now(),
-        };
-
-        cache.insert(path.into(), file);
+        // contents are fetched lazily by read()
 
         /* reading a file requires
         open
@@ -485,7 +935,7 @@ impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
 
     fn read(
         &self,
-        _req: RequestInfo,
+        req: RequestInfo,
         path: &std::path::Path,
         _fh: u64,
         offset: u64,
@@ -496,19 +946,13 @@ impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
         let path = path.to_str().unwrap();
         // println!("read {} offset {} size {}", path, offset, size);
 
-        let file_cache = self.file_cache.lock().unwrap();
-        let file = match file_cache.get(path) {
-            Some(file) => file,
-            _ => {
-                return callback(Err(libc::ENOENT)); // EACCES
+        match process::on_behalf_of(req.pid, || self.read_blocks(path, offset, size)) {
+            Ok(data) => callback(Ok(&data)),
+            Err(e) => {
+                println!("Error: {} {}", path, e);
+                callback(Err(e.errno()))
             }
-        };
-
-        let contents = &file.contents;
-        let slice =
-            &contents[offset as usize..(offset as usize + size as usize).min(contents.len())];
-
-        callback(Ok(slice))
+        }
     }
 
     fn write(
@@ -559,11 +1003,12 @@ impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
         Err(libc::ENOSYS)
     }
 
-    fn opendir(&self, _req: RequestInfo, path: &std::path::Path, _flags: u32) -> ResultOpen {
+    fn opendir(&self, req: RequestInfo, path: &std::path::Path, _flags: u32) -> ResultOpen {
         self.track("opendir", path);
 
         let path_str = path.to_str().unwrap();
-        self.get_or_update_metadata(path_str);
+        process::on_behalf_of(req.pid, || self.get_or_update_metadata(path_str))
+            .map_err(|e| e.errno())?;
 
         let cache = self.cache.lock().unwrap();
 
@@ -577,10 +1022,9 @@ impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
     }
 
     // we optimistically think the directory should be preload in cache!
-    fn readdir(&self, _req: RequestInfo, path: &std::path::Path, _fh: u64) -> ResultReaddir {
+    fn readdir(&self, req: RequestInfo, path: &std::path::Path, _fh: u64) -> ResultReaddir {
         self.track("readdir", path);
-        let entries = self.get_entries(path);
-        Ok(entries)
+        process::on_behalf_of(req.pid, || self.get_entries(path)).map_err(|e| e.errno())
     }
 
     fn releasedir(
@@ -687,15 +1131,14 @@ impl<T: CmdRunner> FilesystemMT for SshFuseFs<T> {
 
 #[test]
 fn test_runner() {
-    use crate::ls::parse_long_list;
-    use std::process::Output;
+    use crate::ls::{parse_long_list, Dialect, ListFormat};
 
     struct TestRunner {
         count: AtomicU32,
     }
 
     impl CmdRunner for TestRunner {
-        fn fetch_path(&self, path: &str) -> Option<Vec<FileMeta>> {
+        fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
             println!("fetch_path {}", path);
             self.count.fetch_add(1, Ordering::Relaxed);
             match path {
@@ -705,21 +1148,25 @@ fn test_runner() {
                     drwxr-xr-x   3 root root  4096 Jun 25 06:00 boot
                     drwxr-xr-x  14 root root  3160 Dec 17  2020 dev
                     drwxr-xr-x 105 root root  4096 Jun 25 21:26 etc";
-                    Some(parse_long_list(ls))
+                    Ok(parse_long_list(ls, &ListFormat::utc()))
                 }
                 "/boot/" => {
                     let ls = r"total 128M
                     -rw------- 1 root root 3.7M Jul  4  2019 System.map-4.15.0-1044-aws
                     -rw------- 1 root root 3.7M Nov  7  2019 System.map-4.15.0-1054-aws
                     -rw------- 1 root root 4.3M May 14 16:08 System.map-5.4.0-1049-aws";
-                    Some(parse_long_list(ls))
+                    Ok(parse_long_list(ls, &ListFormat::utc()))
                 }
-                _ => None,
+                _ => Err(RunnerError::NotFound(path.into())),
             }
         }
 
-        fn fetch_file(&self, path: &str) -> Output {
-            todo!();
+        fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+            Err(RunnerError::NotFound(path.into()))
+        }
+
+        fn fetch_range(&self, path: &str, _offset: u64, _len: u64) -> Result<Vec<u8>, RunnerError> {
+            Err(RunnerError::NotFound(path.into()))
         }
     }
 
@@ -731,22 +1178,339 @@ fn test_runner() {
     assert_eq!(filesystem.cache.lock().unwrap().contains_key(""), false);
     assert_eq!(filesystem.runner.count.load(Ordering::Relaxed), 0);
 
-    filesystem.get_or_update_metadata("/");
+    filesystem.get_or_update_metadata("/").unwrap();
     assert_eq!(filesystem.cache.lock().unwrap().contains_key(""), true);
     assert_eq!(filesystem.runner.count.load(Ordering::Relaxed), 1);
 
     // make sure that it's reading from cache
-    filesystem.get_or_update_metadata("/");
+    filesystem.get_or_update_metadata("/").unwrap();
     assert_eq!(filesystem.runner.count.load(Ordering::Relaxed), 1);
     // println!("cache: {:#?}", filesystem.cache);
 
     // still reading from cache but only attrs are needed, could spin
     // things up in the background
-    filesystem.get_or_update_metadata("/boot");
+    filesystem.get_or_update_metadata("/boot").unwrap();
     assert_eq!(filesystem.runner.count.load(Ordering::Relaxed), 1);
 
-    assert_eq!(filesystem.get_dir_list_from_cache("/").len(), 4);
-
-    assert_eq!(filesystem.get_dir_list_from_cache("/boot").len(), 3);
+    // without stat, a file's parent is listed, siblings included
+    let no_stat = SshFuseFs::new(TestRunner {
+        count: Default::default(),
+    });
+    no_stat
+        .get_or_update_metadata("/boot/System.map-4.15.0-1044-aws")
+        .unwrap();
+    no_stat
+        .get_or_update_metadata("/boot/System.map-5.4.0-1049-aws")
+        .unwrap();
+    // and one missing from it is known to be
+    no_stat.get_or_update_metadata("/boot/nope").unwrap();
+    assert!(!no_stat.cache.lock().unwrap().contains_key("/boot/nope"));
+    assert_eq!(no_stat.runner.count.load(Ordering::Relaxed), 1);
+
+    assert_eq!(filesystem.get_dir_list_from_cache("/").unwrap().len(), 4);
+
+    assert_eq!(
+        filesystem.get_dir_list_from_cache("/boot").unwrap().len(),
+        3
+    );
     assert_eq!(filesystem.runner.count.load(Ordering::Relaxed), 2);
+
+    // failures are reported, not cached as empty directories
+    assert_eq!(
+        filesystem.get_dir_list_from_cache("/missing").unwrap_err(),
+        RunnerError::NotFound("/missing/".into())
+    );
+    assert!(!filesystem.cache.lock().unwrap().contains_key("/missing"));
+    assert_eq!(
+        filesystem
+            .read_blocks("/missing", 0, 10)
+            .unwrap_err()
+            .errno(),
+        libc::ENOENT
+    );
+
+    // Solaris doors have no file type of their own, but aren't files
+    let solaris = parse_long_list(
+        include_str!("../fixtures/ls/solaris.txt"),
+        &ListFormat::utc().with_dialect(Dialect::Solaris),
+    );
+    SshFuseFs::<TestRunner>::populate_dir_cache(&filesystem.cache, "/etc", solaris);
+    let req = RequestInfo {
+        unique: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+    let (_, attr) = filesystem
+        .getattr(req, Path::new("/etc/name_service_door"), None)
+        .unwrap();
+    assert!(matches!(attr.kind, FileType::Socket));
+    assert_eq!(attr.perm, 0o644);
+    let (_, attr) = filesystem
+        .getattr(req, Path::new("/etc/bin"), None)
+        .unwrap();
+    assert!(matches!(attr.kind, FileType::Directory));
+    assert_eq!(attr.perm, 0o755);
+}
+
+#[test]
+fn test_local_end_to_end() {
+    use crate::local::LocalRunner;
+    use crate::testing::TempTree;
+    use std::os::unix::fs::MetadataExt;
+
+    let root = TempTree::new("mount");
+    fs::create_dir_all(root.join("a/b")).unwrap();
+    fs::write(root.join("a/b/c.txt"), "nested").unwrap();
+    fs::write(root.join("top.txt"), "hello").unwrap();
+    std::os::unix::fs::symlink("a/b", root.join("link")).unwrap();
+
+    let filesystem = SshFuseFs::new(LocalRunner::new(root.to_path_buf()));
+    let req = RequestInfo {
+        unique: 0,
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    let (_, attr) = filesystem
+        .getattr(req, Path::new("/top.txt"), None)
+        .unwrap();
+    assert_eq!(attr.size, 5);
+    assert!(matches!(attr.kind, FileType::RegularFile));
+    let local = fs::metadata(root.join("top.txt")).unwrap();
+    assert_eq!((attr.uid, attr.gid), (local.uid(), local.gid()));
+    // `ls -l` only names owners, but for those without a name
+    let listed = FileMeta {
+        owner_name: "1000".into(),
+        owner_group: "staff".into(),
+        ..Default::default()
+    };
+    assert_eq!(owner_ids(&listed), (1000, unsafe { libc::getgid() }));
+
+    let (_, attr) = filesystem.getattr(req, Path::new("/a"), None).unwrap();
+    assert!(matches!(attr.kind, FileType::Directory));
+
+    // links are left to the kernel to follow
+    let (_, attr) = filesystem.getattr(req, Path::new("/link"), None).unwrap();
+    assert!(matches!(attr.kind, FileType::Symlink));
+    assert_eq!(
+        filesystem.readlink(req, Path::new("/link")).unwrap(),
+        b"a/b"
+    );
+    assert_eq!(
+        filesystem.readlink(req, Path::new("/top.txt")).unwrap_err(),
+        libc::EINVAL
+    );
+
+    assert_eq!(
+        filesystem
+            .getattr(req, Path::new("/nope"), None)
+            .unwrap_err(),
+        libc::ENOENT
+    );
+
+    filesystem.opendir(req, Path::new("/a/b"), 0).unwrap();
+    let entries = filesystem.readdir(req, Path::new("/a/b"), 0).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].name, "c.txt");
+
+    filesystem.open(req, Path::new("/a/b/c.txt"), 0).unwrap();
+    assert_eq!(
+        filesystem.read_blocks("/a/b/c.txt", 0, 4096).unwrap(),
+        b"nested"
+    );
+    assert_eq!(filesystem.read_blocks("/a/b/c.txt", 2, 3).unwrap(), b"ste");
+    assert_eq!(
+        filesystem
+            .read_blocks("/nope", 0, 4096)
+            .unwrap_err()
+            .errno(),
+        libc::ENOENT
+    );
+}
+
+#[test]
+fn test_block_reads() {
+    use crate::local::LocalRunner;
+    use crate::testing::TempTree;
+
+    let root = TempTree::new("blocks");
+    let contents = (0..BLOCK_SIZE as u32 * 2)
+        .flat_map(|i| i.to_le_bytes())
+        .collect::<Vec<_>>();
+    fs::write(root.join("big"), &contents).unwrap();
+
+    let filesystem = SshFuseFs::new(LocalRunner::new(root.to_path_buf()));
+    let cached = |fs: &SshFuseFs<LocalRunner>| {
+        let mut blocks = fs
+            .block_cache
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|(_, i)| *i)
+            .collect::<Vec<_>>();
+        blocks.sort();
+        blocks
+    };
+
+    // only the block holding the range is fetched
+    let offset = BLOCK_SIZE * 3 + 10;
+    assert_eq!(
+        filesystem.read_blocks("/big", offset, 100).unwrap(),
+        &contents[offset as usize..offset as usize + 100]
+    );
+    assert_eq!(cached(&filesystem), vec![3]);
+
+    // a read across a boundary pulls in the neighbours
+    let offset = BLOCK_SIZE * 5 - 10;
+    assert_eq!(
+        filesystem.read_blocks("/big", offset, 20).unwrap(),
+        &contents[offset as usize..offset as usize + 20]
+    );
+    assert_eq!(cached(&filesystem), vec![3, 4, 5]);
+
+    // reads at the end are short, and past the end empty
+    let len = contents.len() as u64;
+    assert_eq!(
+        filesystem.read_blocks("/big", len - 5, 100).unwrap().len(),
+        5
+    );
+    assert!(filesystem
+        .read_blocks("/big", len + 5, 100)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_verify_size() {
+    use crate::local::LocalRunner;
+    use crate::testing::{Forwarding, TempTree};
+
+    let root = TempTree::new("verify");
+    fs::write(root.join("f"), "contents").unwrap();
+    // loses the last byte of whatever is read
+    let truncating = || {
+        Forwarding::new(LocalRunner::new(root.to_path_buf())).with_map_contents(|mut data| {
+            data.pop();
+            data
+        })
+    };
+
+    let verify = FsOptions {
+        verify: true,
+        ..Default::default()
+    };
+    let filesystem = SshFuseFs::new(truncating()).with_options(verify);
+    filesystem.get_or_update_metadata("/f").unwrap();
+    assert_eq!(
+        filesystem.read_blocks("/f", 0, 4096).unwrap_err().errno(),
+        libc::EIO
+    );
+    // fetched again, whole, after listing the directory again, each time
+    assert_eq!(
+        filesystem.runner.calls_to("fetch_file").len() as u32,
+        MAX_VERIFY_ATTEMPTS + 1
+    );
+
+    // unverified, the short read goes through
+    let filesystem = SshFuseFs::new(truncating());
+    filesystem.get_or_update_metadata("/f").unwrap();
+    assert_eq!(filesystem.read_blocks("/f", 0, 4096).unwrap(), b"content");
+
+    // a file that changed since it was listed is picked up
+    let filesystem = SshFuseFs::new(LocalRunner::new(root.to_path_buf())).with_options(verify);
+    filesystem.get_or_update_metadata("/f").unwrap();
+    fs::write(root.join("f"), "more contents").unwrap();
+    assert_eq!(
+        filesystem.read_blocks("/f", 0, 4096).unwrap(),
+        b"more contents"
+    );
+}
+
+#[test]
+fn test_batched_refresh() {
+    use crate::local::LocalRunner;
+    use crate::testing::{Forwarding, TempTree};
+
+    let root = TempTree::new("batched");
+    fs::create_dir_all(root.join("a/deeper")).unwrap();
+    fs::create_dir_all(root.join("b")).unwrap();
+    fs::create_dir_all(root.join("c")).unwrap();
+
+    let filesystem = SshFuseFs::new(Forwarding::new(LocalRunner::new(root.to_path_buf())));
+    let batches = || filesystem.runner.calls_to("fetch_paths");
+    for dir in &["/", "/a", "/b", "/a/deeper"] {
+        filesystem.get_dir_list_from_cache(dir).unwrap();
+    }
+    assert_eq!(batches().len(), 4);
+
+    // everything expires
+    for meta in filesystem.cache.lock().unwrap().values_mut() {
+        meta.last_updated -= TTL * 2;
+    }
+    fs::create_dir_all(root.join("b/new")).unwrap();
+
+    // the directories browsed below come along with their parent
+    assert_eq!(filesystem.get_dir_list_from_cache("/").unwrap().len(), 3);
+    let mut batch = batches().pop().unwrap();
+    batch.sort();
+    assert_eq!(batch, &["/", "/a/", "/b/"]);
+    let count = batches().len();
+    assert_eq!(filesystem.get_dir_list_from_cache("/b").unwrap().len(), 1);
+    assert_eq!(batches().len(), count);
+}
+
+#[test]
+fn test_prefetch() {
+    use crate::local::LocalRunner;
+    use crate::testing::{Forwarding, TempTree};
+
+    let root = TempTree::new("prefetch");
+    fs::create_dir_all(root.join("a/b/c/d")).unwrap();
+    fs::write(root.join("a/b/file"), "x").unwrap();
+
+    let runner = LocalRunner::new(root.to_path_buf()).with_latency(Duration::from_millis(50));
+    let filesystem = SshFuseFs::new(Forwarding::new(runner)).with_options(FsOptions {
+        prefetch_depth: 2,
+        prefetch_max: 100,
+        ..Default::default()
+    });
+    let listed = |key: &str| {
+        let cache = filesystem.cache.lock().unwrap();
+        cache.get(key).map(|m| m.updated).unwrap_or(false)
+    };
+    let settle = || {
+        let pending = &filesystem.prefetcher.as_ref().unwrap().pending;
+        let started = Instant::now();
+        while !pending.lock().unwrap().is_empty() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    let trees = || filesystem.runner.calls_to("fetch_tree");
+
+    filesystem.get_dir_list_from_cache("/").unwrap();
+    // browsing in before the prefetch of `/` is done doesn't queue another
+    filesystem.get_dir_list_from_cache("/a").unwrap();
+    settle();
+    assert_eq!(trees(), vec![vec!["/".to_string()]]);
+
+    // two levels below `/` are listed, not further
+    assert!(listed("/a"));
+    assert!(listed("/a/b"));
+    assert!(!listed("/a/b/c"));
+    assert_eq!(filesystem.cache.lock().unwrap()["/a/b/file"].size, 1);
+
+    // relisting `/` while what's under it is fresh doesn't prefetch it again
+    filesystem
+        .cache
+        .lock()
+        .unwrap()
+        .get_mut("")
+        .unwrap()
+        .updated = false;
+    filesystem.get_dir_list_from_cache("/").unwrap();
+    settle();
+    assert_eq!(trees().len(), 1);
+    assert!(listed("/a/b"));
 }
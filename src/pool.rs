@@ -0,0 +1,299 @@
+use std::{
+    cell::Cell,
+    collections::BTreeSet,
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use crate::cmd::{CmdRunner, Listings, Timeouts};
+use crate::error::RunnerError;
+use crate::ls::FileMeta;
+use crate::process::Deadline;
+
+/// how often a waiting command checks whether it was cancelled
+const POLL: Duration = Duration::from_millis(20);
+
+/// who a remote command is for. interactive requests (someone running
+/// `ls` or `cat` on the mount) are served before background work like
+/// prefetching
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Interactive,
+    Background,
+}
+
+thread_local! {
+    static PRIORITY: Cell<Priority> = const { Cell::new(Priority::Interactive) };
+}
+
+/// runs `f` with every command it starts queued at `priority`
+pub fn with_priority<T>(priority: Priority, f: impl FnOnce() -> T) -> T {
+    let previous = PRIORITY.with(|p| p.replace(priority));
+    let result = f();
+    PRIORITY.with(|p| p.set(previous));
+    result
+}
+
+/// A CmdRunner limiting how many commands run on the remote at once, so
+/// a burst of FUSE requests (eg. a recursive `find`) doesn't trip sshd's
+/// MaxStartups/MaxSessions. waiting commands are served by priority, then
+/// in the order they arrived.
+pub struct Pool<R> {
+    runner: R,
+    max: usize,
+    /// a command waiting longer than its own timeout gives up
+    timeouts: Timeouts,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    next_ticket: u64,
+    waiting: BTreeSet<(Priority, u64)>,
+}
+
+/// a slot in the pool, given back when dropped
+struct Permit<'a, R> {
+    pool: &'a Pool<R>,
+}
+
+impl<R> Drop for Permit<'_, R> {
+    fn drop(&mut self) {
+        self.pool.state.lock().unwrap().running -= 1;
+        self.pool.changed.notify_all();
+    }
+}
+
+impl<R: CmdRunner> Pool<R> {
+    pub fn new(runner: R, max: usize) -> Self {
+        Self {
+            runner,
+            max: max.max(1),
+            timeouts: Timeouts::default(),
+            state: Default::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// waits for a slot, giving up once `deadline` passes or the request
+    /// is cancelled
+    fn acquire(&self, deadline: &Deadline) -> Result<Permit<'_, R>, RunnerError> {
+        let mut state = self.state.lock().unwrap();
+
+        let ticket = (PRIORITY.with(|p| p.get()), state.next_ticket);
+        state.next_ticket += 1;
+        state.waiting.insert(ticket);
+
+        while state.running >= self.max || state.waiting.iter().next() != Some(&ticket) {
+            if let Err(e) = deadline.check() {
+                state.waiting.remove(&ticket);
+                // the one behind may be first in line now
+                self.changed.notify_all();
+                return Err(e);
+            }
+            // woken up now and then to notice cancellation
+            state = self.changed.wait_timeout(state, POLL).unwrap().0;
+        }
+
+        state.waiting.remove(&ticket);
+        state.running += 1;
+        // whoever is next in line may fit too
+        self.changed.notify_all();
+
+        Ok(Permit { pool: self })
+    }
+
+    fn acquire_for_list(&self) -> Result<Permit<'_, R>, RunnerError> {
+        self.acquire(&Deadline::after(self.timeouts.list))
+    }
+
+    fn acquire_for_read(&self) -> Result<Permit<'_, R>, RunnerError> {
+        self.acquire(&Deadline::after(self.timeouts.read))
+    }
+}
+
+impl<R: CmdRunner> CmdRunner for Pool<R> {
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        let _permit = self.acquire_for_list()?;
+        self.runner.fetch_path(path)
+    }
+
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+        let _permit = self.acquire_for_read()?;
+        self.runner.fetch_file(path)
+    }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        let _permit = self.acquire_for_read()?;
+        self.runner.fetch_range(path, offset, len)
+    }
+
+    fn fetch_paths(&self, paths: &[&str]) -> Listings {
+        let _permit = match self.acquire_for_list() {
+            Ok(permit) => permit,
+            Err(e) => {
+                return paths
+                    .iter()
+                    .map(|p| (p.to_string(), Err(e.clone())))
+                    .collect()
+            }
+        };
+        self.runner.fetch_paths(paths)
+    }
+
+    fn fetch_tree(&self, path: &str, depth: u32, max_entries: usize) -> Listings {
+        let _permit = match self.acquire_for_list() {
+            Ok(permit) => permit,
+            // nothing listed in full
+            Err(_) => return Listings::new(),
+        };
+        self.runner.fetch_tree(path, depth, max_entries)
+    }
+
+    fn fetch_meta(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        let _permit = self.acquire_for_list()?;
+        self.runner.fetch_meta(path)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.state.lock().unwrap().waiting.len()
+    }
+}
+
+#[cfg(test)]
+struct RecordingRunner {
+    /// commands currently running, and the most seen at once
+    running: Mutex<(usize, usize)>,
+    order: Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl CmdRunner for RecordingRunner {
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        {
+            let mut running = self.running.lock().unwrap();
+            running.0 += 1;
+            running.1 = running.1.max(running.0);
+        }
+        self.order.lock().unwrap().push(path.to_string());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        self.running.lock().unwrap().0 -= 1;
+        Ok(vec![])
+    }
+
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+        self.fetch_path(path).map(|_| vec![])
+    }
+
+    fn fetch_range(&self, path: &str, _offset: u64, _len: u64) -> Result<Vec<u8>, RunnerError> {
+        self.fetch_file(path)
+    }
+}
+
+#[test]
+fn test_pool_limit() {
+    use std::sync::Arc;
+
+    let pool = Arc::new(Pool::new(
+        RecordingRunner {
+            running: Default::default(),
+            order: Default::default(),
+        },
+        3,
+    ));
+
+    let threads = (0..12)
+        .map(|i| {
+            let pool = pool.clone();
+            std::thread::spawn(move || pool.fetch_path(&format!("/{}", i)).unwrap())
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    assert_eq!(pool.runner.order.lock().unwrap().len(), 12);
+    assert_eq!(pool.runner.running.lock().unwrap().1, 3);
+    assert_eq!(pool.queue_depth(), 0);
+}
+
+#[test]
+fn test_pool_priority() {
+    use std::{sync::Arc, thread};
+
+    let pool = Arc::new(Pool::new(
+        RecordingRunner {
+            running: Default::default(),
+            order: Default::default(),
+        },
+        1,
+    ));
+
+    // hold the only slot while the queue fills up
+    let permit = pool
+        .acquire(&Deadline::after(Duration::from_secs(5)))
+        .unwrap();
+
+    let mut threads = vec![];
+    for (name, priority) in &[
+        ("/background 1", Priority::Background),
+        ("/interactive 1", Priority::Interactive),
+        ("/background 2", Priority::Background),
+        ("/interactive 2", Priority::Interactive),
+    ] {
+        let shared = pool.clone();
+        let (name, priority) = (name.to_string(), *priority);
+        threads.push(thread::spawn(move || {
+            with_priority(priority, || shared.fetch_path(&name).unwrap())
+        }));
+
+        // let each one queue up before the next
+        let queued = threads.len();
+        while pool.queue_depth() < queued {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    drop(permit);
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    assert_eq!(
+        *pool.runner.order.lock().unwrap(),
+        &[
+            "/interactive 1",
+            "/interactive 2",
+            "/background 1",
+            "/background 2"
+        ]
+    );
+}
+
+#[test]
+fn test_pool_timeout() {
+    let pool = Pool::new(
+        RecordingRunner {
+            running: Default::default(),
+            order: Default::default(),
+        },
+        1,
+    );
+
+    let _permit = pool
+        .acquire(&Deadline::after(Duration::from_secs(5)))
+        .unwrap();
+    assert_eq!(
+        pool.acquire(&Deadline::after(Duration::from_millis(50)))
+            .err(),
+        Some(RunnerError::Timeout)
+    );
+    assert_eq!(pool.queue_depth(), 0);
+}
@@ -0,0 +1,191 @@
+use std::{
+    cell::Cell,
+    io::{self, Read},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::error::RunnerError;
+
+/// longest pause between two checks on a running command
+const MAX_POLL: Duration = Duration::from_millis(20);
+
+/// bumped to cancel every command running at the time
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// pid of the process whose filesystem request this thread is serving
+    static REQUESTER: Cell<u32> = const { Cell::new(0) };
+}
+
+/// cancels every remote command in flight, eg. when unmounting
+pub fn cancel_all() {
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+}
+
+/// runs `f` on behalf of process `pid`. fuse_mt doesn't pass interrupts
+/// on, so commands started by `f` are instead cancelled once that process
+/// is gone, eg. killed while blocked on a slow read
+pub fn on_behalf_of<T>(pid: u32, f: impl FnOnce() -> T) -> T {
+    let previous = REQUESTER.with(|r| r.replace(pid));
+    let result = f();
+    REQUESTER.with(|r| r.set(previous));
+    result
+}
+
+/// tracks whether an operation started on this thread should still go on
+pub struct Deadline {
+    at: Instant,
+    generation: u64,
+    requester: u32,
+}
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self {
+            at: Instant::now() + timeout,
+            generation: GENERATION.load(Ordering::SeqCst),
+            requester: REQUESTER.with(|r| r.get()),
+        }
+    }
+
+    /// `Timeout` or `Cancelled` once the operation should be abandoned
+    pub fn check(&self) -> Result<(), RunnerError> {
+        if Instant::now() >= self.at {
+            return Err(RunnerError::Timeout);
+        }
+
+        if GENERATION.load(Ordering::SeqCst) != self.generation || process_gone(self.requester) {
+            return Err(RunnerError::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    /// sleeps for `pause`, or until the operation should be abandoned
+    pub fn sleep(&self, pause: Duration) -> Result<(), RunnerError> {
+        let until = Instant::now() + pause;
+        loop {
+            self.check()?;
+            let now = Instant::now();
+            if now >= until {
+                return Ok(());
+            }
+            thread::sleep((until - now).min(MAX_POLL));
+        }
+    }
+}
+
+fn process_gone(pid: u32) -> bool {
+    if pid == 0 {
+        return false;
+    }
+
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == -1 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+}
+
+/// like `Command::output`, but the child is killed when `deadline` passes
+/// or the command gets cancelled, instead of waiting on it forever
+pub fn output_until(mut cmd: Command, deadline: &Deadline) -> Result<Output, RunnerError> {
+    let mut child = spawn(
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+    )?;
+
+    // drained on the side so a chatty child can't block on a full pipe
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let status = wait_until(&mut child, deadline)?;
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// like `output_until` with all of the child's output discarded, for
+/// commands that leave something running on their pipes, eg. `ssh -f`
+pub fn status_until(mut cmd: Command, deadline: &Deadline) -> Result<ExitStatus, RunnerError> {
+    let mut child = spawn(
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    )?;
+    wait_until(&mut child, deadline)
+}
+
+fn spawn(cmd: &mut Command) -> Result<Child, RunnerError> {
+    cmd.spawn().map_err(|e| match e.kind() {
+        // eg. docker or kubectl not installed, not a missing remote file
+        io::ErrorKind::NotFound => {
+            RunnerError::CommandMissing(cmd.get_program().to_string_lossy().into_owned())
+        }
+        _ => e.into(),
+    })
+}
+
+fn wait_until(child: &mut Child, deadline: &Deadline) -> Result<ExitStatus, RunnerError> {
+    let mut pause = Duration::from_millis(1);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        if let Err(e) = deadline.check() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(e);
+        }
+
+        thread::sleep(pause);
+        pause = (pause * 2).min(MAX_POLL);
+    }
+}
+
+fn read_in_background(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+#[test]
+fn test_output_until() {
+    let sh = |script: &str| {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    };
+    let output_with_timeout =
+        |cmd: Command, timeout: Duration| output_until(cmd, &Deadline::after(timeout));
+
+    let output = output_with_timeout(sh("echo out; echo err >&2"), Duration::from_secs(5)).unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+
+    let started = Instant::now();
+    assert_eq!(
+        output_with_timeout(sh("exec sleep 10"), Duration::from_millis(100)).unwrap_err(),
+        RunnerError::Timeout
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // the requesting process going away cancels the command
+    let mut gone = Command::new("true").spawn().unwrap();
+    let pid = gone.id();
+    gone.wait().unwrap();
+    let result = on_behalf_of(pid, || {
+        output_with_timeout(sh("exec sleep 10"), Duration::from_secs(5))
+    });
+    assert_eq!(result.unwrap_err(), RunnerError::Cancelled);
+}
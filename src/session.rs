@@ -0,0 +1,220 @@
+use std::{
+    fs, io,
+    os::unix::fs::DirBuilderExt,
+    path::PathBuf,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    askpass::Askpass,
+    error::RunnerError,
+    process::{self, Deadline},
+};
+
+/// how often the master is probed with `ssh -O check`
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+static SOCKET_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// A ssh ControlMaster managed by sshfuse. Commands are run with
+/// `ControlPath` pointing to our socket so they reuse the master's
+/// connection instead of paying for a full handshake each time.
+#[derive(Debug)]
+pub struct ControlMaster {
+    /// private directory the socket is in, so no one else can put theirs
+    /// in its place
+    dir: PathBuf,
+    /// whether we made `dir`. until then clients don't use the socket
+    dir_created: AtomicBool,
+    socket: PathBuf,
+    /// ssh options and destination the master connects with
+    ssh_args: Vec<String>,
+    /// when the master was last known to be alive
+    last_checked: Mutex<Option<Instant>>,
+    /// held by the one thread checking or starting the master
+    starting: Mutex<()>,
+    /// how long checking or starting the master may take
+    timeout: Duration,
+    /// answers the master's password prompts
+    askpass: Option<Arc<Askpass>>,
+}
+
+impl ControlMaster {
+    pub fn new(ssh_args: Vec<String>) -> Self {
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        let dir = runtime_dir.join(format!(
+            "sshfuse-{}-{}",
+            std::process::id(),
+            SOCKET_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        Self {
+            socket: dir.join("master.sock"),
+            dir,
+            dir_created: AtomicBool::new(false),
+            ssh_args,
+            last_checked: Mutex::new(None),
+            starting: Mutex::new(()),
+            timeout: Duration::from_secs(10),
+            askpass: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_askpass(mut self, askpass: Arc<Askpass>) -> Self {
+        self.askpass = Some(askpass);
+        self
+    }
+
+    /// ssh options that make a client go through the master socket.
+    /// if the master is gone, or its directory couldn't be made, the client
+    /// falls back to a direct connection
+    pub fn client_options(&self) -> Vec<String> {
+        let path = if self.dir_created.load(Ordering::Relaxed) {
+            self.socket.display().to_string()
+        } else {
+            "none".into()
+        };
+
+        vec![
+            "-o".into(),
+            "ControlMaster=no".into(),
+            "-o".into(),
+            format!("ControlPath={}", path),
+        ]
+    }
+
+    /// forgets that the master was alive, so the next `ensure` probes it
+    /// again and restarts it if the connection dropped
+    pub fn reset(&self) {
+        *self.last_checked.lock().unwrap() = None;
+    }
+
+    /// makes sure the master is running, (re)starting it when it's missing
+    /// or dead
+    pub fn ensure(&self) -> Result<(), RunnerError> {
+        if self.recently_checked() {
+            return Ok(());
+        }
+
+        // only one thread checks or starts the master. the others go on
+        // without waiting, on their own connection if it's down
+        let _starting = match self.starting.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return Ok(()),
+        };
+        if self.recently_checked() {
+            return Ok(());
+        }
+
+        let ours = self.dir_created.load(Ordering::Relaxed);
+        if ours && self.socket.exists() && self.control("check") {
+            *self.last_checked.lock().unwrap() = Some(Instant::now());
+            return Ok(());
+        }
+
+        self.create_dir()?;
+        // a stale socket from a dead master would stop a new one from binding
+        let _ = fs::remove_file(&self.socket);
+
+        // -f backgrounds once authenticated so ssh exits when the master is
+        // ready to accept clients
+        let mut master = Command::new("ssh");
+        master
+            .args(["-M", "-N", "-f"])
+            .args(["-o", "ServerAliveInterval=15"])
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.socket.display()))
+            .args(&self.ssh_args);
+        if let Some(askpass) = &self.askpass {
+            askpass.apply(&mut master);
+        }
+        let status = process::status_until(master, &Deadline::after(self.timeout))?;
+
+        if !status.success() {
+            return Err(RunnerError::ConnectionLost(format!(
+                "ssh master exited with {}",
+                status
+            )));
+        }
+
+        *self.last_checked.lock().unwrap() = Some(Instant::now());
+        Ok(())
+    }
+
+    fn recently_checked(&self) -> bool {
+        match *self.last_checked.lock().unwrap() {
+            Some(checked) => checked.elapsed() < CHECK_INTERVAL,
+            None => false,
+        }
+    }
+
+    /// makes the socket's directory, readable by us only. one that's
+    /// already there isn't ours to trust
+    fn create_dir(&self) -> io::Result<()> {
+        if self.dir_created.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        fs::DirBuilder::new().mode(0o700).create(&self.dir)?;
+        self.dir_created.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// sends a control command (`check`, `exit`) to the master
+    fn control(&self, command: &str) -> bool {
+        let mut control = Command::new("ssh");
+        control
+            .args(["-O", command])
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.socket.display()))
+            .args(&self.ssh_args);
+        process::status_until(control, &Deadline::after(self.timeout))
+            .map(|s| s.success())
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for ControlMaster {
+    fn drop(&mut self) {
+        if !self.dir_created.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.socket.exists() {
+            self.control("exit");
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn test_socket_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let master = ControlMaster::new(vec![]);
+    master.create_dir().unwrap();
+    let mode = fs::metadata(&master.dir).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+    assert!(master.client_options()[3].ends_with("/master.sock"));
+    let dir = master.dir.clone();
+    drop(master);
+    assert!(!dir.exists());
+
+    // someone else's directory in its place isn't used
+    let master = ControlMaster::new(vec![]);
+    fs::create_dir(&master.dir).unwrap();
+    assert!(master.ensure().is_err());
+    assert_eq!(master.client_options()[3], "ControlPath=none");
+    fs::remove_dir(&master.dir).unwrap();
+}
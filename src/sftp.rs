@@ -0,0 +1,738 @@
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{mpsc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use crate::cmd::{CmdRunner, Timeouts};
+use crate::error::RunnerError;
+use crate::ls::{
+    mode_string, FileKind, FileMeta, FileTime, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT,
+    S_IFSOCK,
+};
+use crate::process::Deadline;
+
+// SFTP v3 (draft-ietf-secsh-filexfer-02) packet types
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_READLINK: u8 = 19;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x1;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x2;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x4;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x8;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const SSH_FXF_READ: u32 = 0x1;
+
+/// size of each READ request when fetching a file
+const READ_CHUNK: u32 = 32 * 1024;
+/// how often a waiting request checks for its deadline or cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type Packet = (u8, Vec<u8>);
+
+/// A CmdRunner speaking SFTP v3 to a `sftp` subsystem (or a local
+/// `sftp-server`), for hosts that don't allow running `ls` and `cat`.
+///
+/// A request that times out or is cancelled takes the server down with it,
+/// as its response would be taken for the next one's; the requests after
+/// that fail with `ConnectionLost`.
+pub struct SftpRunner {
+    channel: Mutex<Channel>,
+    timeouts: Timeouts,
+}
+
+struct Channel {
+    child: Child,
+    writer: BufWriter<ChildStdin>,
+    /// packets read by a background thread, so waiting for them can be
+    /// given up on
+    packets: mpsc::Receiver<io::Result<Packet>>,
+    next_id: u32,
+    /// when the operation in progress is abandoned
+    deadline: Deadline,
+    /// the server was killed or exited
+    closed: bool,
+}
+
+/// attributes from a SSH_FXP_ATTRS / SSH_FXP_NAME response
+#[derive(Debug, Default, Clone)]
+struct Attrs {
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    permissions: Option<u32>,
+    mtime: Option<u32>,
+}
+
+impl Attrs {
+    /// what the file type bits say, `target` being where a link points.
+    /// v3 attributes don't carry device numbers
+    fn kind(&self, target: &str) -> FileKind {
+        match self.permissions.unwrap_or(0) & S_IFMT {
+            S_IFDIR => FileKind::Directory,
+            S_IFLNK => FileKind::Symlink(target.into()),
+            S_IFIFO => FileKind::NamedPipe,
+            S_IFSOCK => FileKind::Socket,
+            S_IFCHR => FileKind::CharDevice { major: 0, minor: 0 },
+            S_IFBLK => FileKind::BlockDevice { major: 0, minor: 0 },
+            _ => FileKind::File,
+        }
+    }
+
+    fn is_link(&self) -> bool {
+        self.permissions
+            .map(|p| p & S_IFMT == S_IFLNK)
+            .unwrap_or(false)
+    }
+}
+
+impl SftpRunner {
+    /// starts `cmd` (eg. `ssh -s host sftp` or a local `sftp-server`) and
+    /// negotiates protocol version 3 over its stdin/stdout
+    pub fn spawn(mut cmd: Command) -> Result<Self, RunnerError> {
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+
+        let writer = BufWriter::new(child.stdin.take().unwrap());
+        let mut reader = BufReader::new(child.stdout.take().unwrap());
+
+        let (sender, packets) = mpsc::channel();
+        thread::spawn(move || loop {
+            let packet = read_packet(&mut reader);
+            let failed = packet.is_err();
+            if sender.send(packet).is_err() || failed {
+                break;
+            }
+        });
+
+        let timeouts = Timeouts::default();
+        let mut channel = Channel {
+            child,
+            writer,
+            packets,
+            next_id: 0,
+            deadline: Deadline::after(timeouts.connect),
+            closed: false,
+        };
+
+        let mut init = vec![];
+        put_u32(&mut init, 3);
+        channel.send(SSH_FXP_INIT, &init)?;
+
+        let (kind, payload) = channel.recv()?;
+        if kind != SSH_FXP_VERSION {
+            return Err(protocol_error("expected SSH_FXP_VERSION").into());
+        }
+        let version = Cursor::new(&payload).u32()?;
+        if version < 3 {
+            return Err(protocol_error("server speaks sftp < 3").into());
+        }
+
+        Ok(Self {
+            channel: Mutex::new(channel),
+            timeouts,
+        })
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// the channel, for an operation given `timeout` to complete
+    fn channel(&self, timeout: Duration) -> Result<MutexGuard<'_, Channel>, RunnerError> {
+        let mut channel = self.channel.lock().unwrap();
+        if channel.closed {
+            return Err(RunnerError::ConnectionLost("sftp server closed".into()));
+        }
+        channel.deadline = Deadline::after(timeout);
+        Ok(channel)
+    }
+
+    /// lists a directory, symlinks along with where they point
+    pub fn read_dir(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        let mut channel = self.channel(self.timeouts.list)?;
+
+        let handle = channel.open_handle(SSH_FXP_OPENDIR, path, None)?;
+
+        let mut entries = vec![];
+        loop {
+            let (kind, payload) = channel.request(SSH_FXP_READDIR, |p| put_str(p, &handle))?;
+            match kind {
+                SSH_FXP_NAME => {
+                    let mut cur = Cursor::new(&payload);
+                    let count = cur.u32()?;
+                    for _ in 0..count {
+                        let name = cur.string()?;
+                        let longname = cur.string()?;
+                        let attrs = cur.attrs()?;
+                        if name != "." && name != ".." {
+                            entries.push((name, longname, attrs));
+                        }
+                    }
+                }
+                SSH_FXP_STATUS => {
+                    let (code, msg) = parse_status(&payload)?;
+                    if code == SSH_FX_EOF {
+                        break;
+                    }
+                    let _ = channel.close_handle(&handle);
+                    return Err(status_error(code, msg).into());
+                }
+                _ => {
+                    let _ = channel.close_handle(&handle);
+                    return Err(protocol_error("unexpected READDIR response").into());
+                }
+            }
+        }
+
+        channel.close_handle(&handle)?;
+
+        let dir = if path.ends_with('/') {
+            path.to_string()
+        } else {
+            format!("{}/", path)
+        };
+
+        let metas = entries
+            .into_iter()
+            .map(|(name, longname, attrs)| {
+                let target = if attrs.is_link() {
+                    channel
+                        .read_link(&format!("{}{}", dir, name))
+                        .unwrap_or_default()
+                } else {
+                    String::new()
+                };
+                to_file_meta(name, &longname, &attrs, &target)
+            })
+            .collect();
+
+        Ok(metas)
+    }
+
+    /// lstat on a single path
+    pub fn lstat(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        let mut channel = self.channel(self.timeouts.list)?;
+        let attrs = channel.attrs_of(SSH_FXP_LSTAT, path)?;
+        let name = path.rsplit('/').next().unwrap_or(path).to_string();
+        let target = if attrs.is_link() {
+            channel.read_link(path)?
+        } else {
+            String::new()
+        };
+        Ok(to_file_meta(name, "", &attrs, &target))
+    }
+
+    /// target of a symbolic link
+    pub fn read_link(&self, path: &str) -> Result<String, RunnerError> {
+        self.channel(self.timeouts.list)?.read_link(path)
+    }
+
+    /// reads a whole file with a sequence of READ requests
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+        self.read_range(path, 0, None)
+    }
+
+    /// reads up to `len` bytes at `offset`, or everything after `offset`
+    /// when `len` is None
+    pub fn read_range(
+        &self,
+        path: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Vec<u8>, RunnerError> {
+        let mut channel = self.channel(self.timeouts.read)?;
+
+        let handle = channel.open_handle(SSH_FXP_OPEN, path, Some(SSH_FXF_READ))?;
+
+        let mut contents = vec![];
+        loop {
+            let remaining = match len {
+                Some(len) if contents.len() as u64 >= len => break,
+                Some(len) => (len - contents.len() as u64).min(READ_CHUNK as u64) as u32,
+                None => READ_CHUNK,
+            };
+            let at = offset + contents.len() as u64;
+
+            let (kind, payload) = channel.request(SSH_FXP_READ, |p| {
+                put_str(p, &handle);
+                p.extend_from_slice(&at.to_be_bytes());
+                put_u32(p, remaining);
+            })?;
+
+            match kind {
+                SSH_FXP_DATA => {
+                    let data = Cursor::new(&payload).bytes()?;
+                    contents.extend_from_slice(data);
+                }
+                SSH_FXP_STATUS => {
+                    let (code, msg) = parse_status(&payload)?;
+                    if code == SSH_FX_EOF {
+                        break;
+                    }
+                    let _ = channel.close_handle(&handle);
+                    return Err(status_error(code, msg).into());
+                }
+                _ => {
+                    let _ = channel.close_handle(&handle);
+                    return Err(protocol_error("unexpected READ response").into());
+                }
+            }
+        }
+
+        channel.close_handle(&handle)?;
+
+        Ok(contents)
+    }
+}
+
+impl CmdRunner for SftpRunner {
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        self.read_dir(path)
+    }
+
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+        self.read_file(path)
+    }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        self.read_range(path, offset, Some(len))
+    }
+
+    fn fetch_meta(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        self.lstat(path)
+    }
+}
+
+impl Drop for SftpRunner {
+    fn drop(&mut self) {
+        let channel = self.channel.get_mut().unwrap();
+        let _ = channel.child.kill();
+        let _ = channel.child.wait();
+    }
+}
+
+impl Channel {
+    fn send(&mut self, kind: u8, payload: &[u8]) -> io::Result<()> {
+        put_u32_to(&mut self.writer, payload.len() as u32 + 1)?;
+        self.writer.write_all(&[kind])?;
+        self.writer.write_all(payload)?;
+        self.writer.flush()
+    }
+
+    /// the next packet, unless the deadline passes first
+    fn recv(&mut self) -> Result<Packet, RunnerError> {
+        loop {
+            match self.packets.recv_timeout(POLL_INTERVAL) {
+                Ok(Ok(packet)) => return Ok(packet),
+                Ok(Err(e)) => return Err(self.close(e.into())),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if let Err(e) = self.deadline.check() {
+                        return Err(self.close(e));
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    let e = RunnerError::ConnectionLost("sftp server exited".into());
+                    return Err(self.close(e));
+                }
+            }
+        }
+    }
+
+    /// kills the server after `e`, the stream can't be trusted anymore
+    fn close(&mut self, e: RunnerError) -> RunnerError {
+        self.closed = true;
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        e
+    }
+
+    /// sends a request with a fresh id and returns the matching response,
+    /// with the id stripped from the payload
+    fn request(
+        &mut self,
+        kind: u8,
+        body: impl FnOnce(&mut Vec<u8>),
+    ) -> Result<Packet, RunnerError> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+
+        let mut payload = vec![];
+        put_u32(&mut payload, id);
+        body(&mut payload);
+        if let Err(e) = self.send(kind, &payload) {
+            return Err(self.close(e.into()));
+        }
+
+        // requests are issued one at a time, so the next response is ours
+        let (kind, payload) = self.recv()?;
+        let mut cur = Cursor::new(&payload);
+        if cur.u32()? != id {
+            let e = protocol_error("response id mismatch").into();
+            return Err(self.close(e));
+        }
+
+        Ok((kind, cur.rest().to_vec()))
+    }
+
+    /// OPEN or OPENDIR, returning the handle
+    fn open_handle(
+        &mut self,
+        kind: u8,
+        path: &str,
+        pflags: Option<u32>,
+    ) -> Result<Vec<u8>, RunnerError> {
+        let (kind, payload) = self.request(kind, |p| {
+            put_str(p, path);
+            if let Some(pflags) = pflags {
+                put_u32(p, pflags);
+                // empty ATTRS
+                put_u32(p, 0);
+            }
+        })?;
+
+        match kind {
+            SSH_FXP_HANDLE => Ok(Cursor::new(&payload).bytes()?.to_vec()),
+            SSH_FXP_STATUS => {
+                let (code, msg) = parse_status(&payload)?;
+                Err(status_error(code, msg).into())
+            }
+            _ => Err(protocol_error("unexpected OPEN response").into()),
+        }
+    }
+
+    fn close_handle(&mut self, handle: &[u8]) -> Result<(), RunnerError> {
+        let (kind, payload) = self.request(SSH_FXP_CLOSE, |p| put_str(p, handle))?;
+        match kind {
+            SSH_FXP_STATUS => match parse_status(&payload)? {
+                (0, _) => Ok(()),
+                (code, msg) => Err(status_error(code, msg).into()),
+            },
+            _ => Err(protocol_error("unexpected CLOSE response").into()),
+        }
+    }
+
+    /// STAT or LSTAT
+    fn read_link(&mut self, path: &str) -> Result<String, RunnerError> {
+        let (kind, payload) = self.request(SSH_FXP_READLINK, |p| put_str(p, path))?;
+        match kind {
+            SSH_FXP_NAME => {
+                let mut cur = Cursor::new(&payload);
+                let _count = cur.u32()?;
+                Ok(cur.string()?)
+            }
+            SSH_FXP_STATUS => {
+                let (code, msg) = parse_status(&payload)?;
+                Err(status_error(code, msg).into())
+            }
+            _ => Err(protocol_error("unexpected READLINK response").into()),
+        }
+    }
+
+    fn attrs_of(&mut self, kind: u8, path: &str) -> Result<Attrs, RunnerError> {
+        let (kind, payload) = self.request(kind, |p| put_str(p, path))?;
+        match kind {
+            SSH_FXP_ATTRS => Ok(Cursor::new(&payload).attrs()?),
+            SSH_FXP_STATUS => {
+                let (code, msg) = parse_status(&payload)?;
+                Err(status_error(code, msg).into())
+            }
+            _ => Err(protocol_error("unexpected STAT response").into()),
+        }
+    }
+}
+
+fn to_file_meta(name: String, longname: &str, attrs: &Attrs, target: &str) -> FileMeta {
+    let permissions = attrs.permissions.unwrap_or(0);
+
+    // longname is a `ls -l` line; it's the only place v3 servers give us
+    // link counts and user/group names
+    let mut long = longname.split_whitespace();
+    let long_perms = long.next();
+    let links = long.next().and_then(|l| l.parse().ok()).unwrap_or(1);
+    let owner_name = long.next().map(String::from);
+    let owner_group = long.next().map(String::from);
+
+    let kind = attrs.kind(target);
+
+    FileMeta {
+        permissions: long_perms
+            .map(String::from)
+            .unwrap_or_else(|| mode_string(kind.mode_char(), permissions)),
+        mode: kind.type_bits() | permissions & 0o7777,
+        kind,
+        links,
+        owner_name: owner_name
+            .or_else(|| attrs.uid.map(|u| u.to_string()))
+            .unwrap_or_default(),
+        owner_group: owner_group
+            .or_else(|| attrs.gid.map(|g| g.to_string()))
+            .unwrap_or_default(),
+        file_size: attrs.size.unwrap_or(0) as usize,
+        name,
+        modified: FileTime {
+            secs: attrs.mtime.unwrap_or(0) as i64,
+            nsecs: 0,
+        },
+        uid: attrs.uid,
+        gid: attrs.gid,
+        ..Default::default()
+    }
+}
+
+/// reads one `<len><type><payload>` packet
+fn read_packet(reader: &mut impl Read) -> io::Result<Packet> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 {
+        return Err(protocol_error("empty packet"));
+    }
+
+    let mut packet = vec![0u8; len];
+    reader.read_exact(&mut packet)?;
+    let kind = packet.remove(0);
+
+    Ok((kind, packet))
+}
+
+fn parse_status(payload: &[u8]) -> io::Result<(u32, String)> {
+    let mut cur = Cursor::new(payload);
+    let code = cur.u32()?;
+    // older servers may omit the message
+    let msg = cur.string().unwrap_or_default();
+    Ok((code, msg))
+}
+
+fn status_error(code: u32, msg: String) -> io::Error {
+    let kind = match code {
+        SSH_FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+        SSH_FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("sftp status {}: {}", code, msg))
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u32_to(w: &mut impl Write, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_be_bytes())
+}
+
+fn put_str(buf: &mut Vec<u8>, s: impl AsRef<[u8]>) {
+    let s = s.as_ref();
+    put_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s);
+}
+
+/// reads big endian fields out of a packet
+struct Cursor<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(protocol_error("short packet"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok((self.u32()? as u64) << 32 | self.u32()? as u64)
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(self.bytes()?).into_owned())
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    fn attrs(&mut self) -> io::Result<Attrs> {
+        let flags = self.u32()?;
+        let mut attrs = Attrs::default();
+
+        if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            attrs.uid = Some(self.u32()?);
+            attrs.gid = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            let _atime = self.u32()?;
+            attrs.mtime = Some(self.u32()?);
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            let count = self.u32()?;
+            for _ in 0..count {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+
+        Ok(attrs)
+    }
+}
+
+/// where distributions usually install OpenSSH's sftp-server
+#[cfg(test)]
+fn find_sftp_server() -> Option<std::path::PathBuf> {
+    [
+        "/usr/lib/openssh/sftp-server",
+        "/usr/libexec/openssh/sftp-server",
+        "/usr/libexec/sftp-server",
+        "/usr/lib/ssh/sftp-server",
+        "/usr/lib/sftp-server",
+    ]
+    .iter()
+    .map(std::path::PathBuf::from)
+    .find(|p| p.exists())
+}
+
+#[test]
+fn test_sftp_server() {
+    use crate::testing::TempTree;
+    use std::fs;
+
+    let server = match find_sftp_server() {
+        Some(server) => server,
+        None => {
+            println!("sftp-server not installed, skipping");
+            return;
+        }
+    };
+
+    let root = TempTree::new("sftp");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("hello.txt"), "hello world").unwrap();
+    let big = (0..100_000u32)
+        .flat_map(|i| i.to_le_bytes())
+        .collect::<Vec<_>>();
+    fs::write(root.join("big.bin"), &big).unwrap();
+    std::os::unix::fs::symlink("hello.txt", root.join("link")).unwrap();
+    std::os::unix::fs::symlink("sub", root.join("dirlink")).unwrap();
+
+    let runner = SftpRunner::spawn(Command::new(server)).unwrap();
+    let root_str = root.to_str().unwrap();
+
+    let mut dir = runner.fetch_path(root_str).unwrap();
+    dir.sort_by(|a, b| a.name.cmp(&b.name));
+    let names = dir.iter().map(|m| m.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec!["big.bin", "dirlink", "hello.txt", "link", "sub"]
+    );
+
+    let hello = &dir[2];
+    assert_eq!(hello.file_size, 11);
+    assert_eq!(hello.kind, FileKind::File);
+    assert!(hello.modified.secs > 0);
+
+    // links are reported as links
+    assert_eq!(dir[1].kind, FileKind::Symlink("sub".into()));
+    assert_eq!(dir[3].kind, FileKind::Symlink("hello.txt".into()));
+    assert!(dir[4].kind.is_dir());
+
+    assert_eq!(
+        runner.read_link(&format!("{}/link", root_str)).unwrap(),
+        "hello.txt"
+    );
+    assert_eq!(
+        runner.lstat(&format!("{}/sub", root_str)).unwrap().name,
+        "sub"
+    );
+
+    assert_eq!(
+        runner.fetch_file(&format!("{}/big.bin", root_str)).unwrap(),
+        big
+    );
+
+    let missing = format!("{}/missing", root_str);
+    assert_eq!(
+        runner.fetch_file(&missing).unwrap_err().errno(),
+        libc::ENOENT
+    );
+    assert_eq!(
+        runner.fetch_path(&missing).unwrap_err().errno(),
+        libc::ENOENT
+    );
+}
+
+#[test]
+fn test_sftp_timeout() {
+    use std::time::Instant;
+
+    // answers INIT, then nothing else
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg("printf '\\000\\000\\000\\005\\002\\000\\000\\000\\003'; exec sleep 60");
+    let runner = SftpRunner::spawn(cmd).unwrap().with_timeouts(Timeouts {
+        list: Duration::from_millis(200),
+        ..Default::default()
+    });
+
+    let started = Instant::now();
+    assert_eq!(runner.fetch_path("/").unwrap_err(), RunnerError::Timeout);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // the stalled server is gone, and so is the channel
+    assert!(matches!(
+        runner.fetch_path("/").unwrap_err(),
+        RunnerError::ConnectionLost(_)
+    ));
+    assert!(runner
+        .channel
+        .lock()
+        .unwrap()
+        .child
+        .try_wait()
+        .unwrap()
+        .is_some());
+}
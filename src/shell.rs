@@ -0,0 +1,162 @@
+/// quotes `s` as a single word for a POSIX shell. the remote side of
+/// ssh always hands the command to a shell, so every path must go
+/// through this before being put in a command line
+pub fn quote(s: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=,:+@%".contains(c);
+
+    if !s.is_empty() && s.chars().all(is_safe) {
+        return s.to_string();
+    }
+
+    // inside single quotes nothing is special except the quote itself,
+    // which has to be closed, escaped and reopened
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// splits a user supplied option string (eg. `--options "-p 2222 -i 'my key'"`)
+/// into argv words, honoring single quotes, double quotes and backslashes
+pub fn split(s: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                for c in &mut chars {
+                    if c == '\'' {
+                        break;
+                    }
+                    word.push(c);
+                }
+            }
+            '"' => {
+                in_word = true;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(c @ '"') | Some(c @ '\\') | Some(c @ '$') | Some(c @ '`') => {
+                                word.push(c)
+                            }
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => word.push('\\'),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                if let Some(c) = chars.next() {
+                    word.push(c);
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+
+    words
+}
+
+/// names that would break or be interpreted by a shell if passed unquoted
+#[cfg(test)]
+const HOSTILE_NAMES: &[&str] = &[
+    "plain",
+    "a b",
+    "  leading and trailing  ",
+    "it's",
+    "''",
+    "\"double\"",
+    "$HOME",
+    "${PATH}",
+    "$(id)",
+    "`id`",
+    "x;rm -rf ~",
+    "a && b || c",
+    "a | b",
+    "> out",
+    "< in",
+    "*",
+    "?",
+    "[a-z]",
+    "{a,b}",
+    "~",
+    "~root",
+    "#comment",
+    "-rf",
+    "--",
+    "!bang",
+    "back\\slash",
+    "tab\there",
+    "new\nline",
+    "carriage\rreturn",
+    "ünïcødé ファイル",
+    "%s %n",
+    "a=b",
+    "",
+];
+
+#[test]
+fn test_quote_hostile_names() {
+    use std::process::Command;
+
+    for name in HOSTILE_NAMES {
+        // round trip through a real shell: printf gets back exactly one
+        // argument, byte for byte
+        let script = format!("printf '%s\\0' {}", quote(name));
+        let output = Command::new("sh").arg("-c").arg(&script).output().unwrap();
+
+        assert!(output.status.success(), "{:?}", name);
+        assert_eq!(
+            output.stdout,
+            format!("{}\0", name).into_bytes(),
+            "quoting {:?} as {}",
+            name,
+            quote(name)
+        );
+    }
+}
+
+#[test]
+fn test_quote() {
+    assert_eq!(quote("/usr/bin"), "/usr/bin");
+    assert_eq!(quote("/tmp/a b"), "'/tmp/a b'");
+    assert_eq!(quote("it's"), r"'it'\''s'");
+    assert_eq!(quote(""), "''");
+}
+
+#[test]
+fn test_split() {
+    assert_eq!(split(""), Vec::<String>::new());
+    assert_eq!(split("-p 2222  -v"), vec!["-p", "2222", "-v"]);
+    assert_eq!(
+        split(r#"-i 'my key' -o "ProxyCommand=ssh -W %h:%p jump""#),
+        vec!["-i", "my key", "-o", "ProxyCommand=ssh -W %h:%p jump"]
+    );
+    assert_eq!(split(r"-i my\ key"), vec!["-i", "my key"]);
+    assert_eq!(split(r#"-o "a\"b""#), vec!["-o", "a\"b"]);
+    assert_eq!(split("-i ''"), vec!["-i", ""]);
+
+    for name in HOSTILE_NAMES {
+        assert_eq!(split(&quote(name)), vec![name.to_string()]);
+    }
+}
@@ -0,0 +1,126 @@
+use std::{fmt, process::Command};
+
+use crate::error::RunnerError;
+
+/// The settings ssh will actually connect with for a destination, after
+/// applying `~/.ssh/config` (host aliases, `Match` blocks...) and the
+/// command line options, as printed by `ssh -G`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SshConfig {
+    pub hostname: String,
+    pub user: String,
+    pub port: u16,
+    pub proxy_jump: Option<String>,
+    /// only those that exist, ssh lists its defaults too
+    pub identity_files: Vec<String>,
+}
+
+impl SshConfig {
+    /// asks ssh how it would connect to `destination` with `options`.
+    /// nothing is connected to
+    pub fn resolve(options: &[String], destination: &str) -> Result<Self, RunnerError> {
+        let output = Command::new("ssh")
+            .arg("-G")
+            .args(options)
+            .arg(destination)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(RunnerError::from_output(&output));
+        }
+
+        Ok(Self::parse(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// parses `ssh -G` output: one lowercase `key value` per line
+    fn parse(out: &str) -> Self {
+        let mut config = Self::default();
+
+        for line in out.lines() {
+            let mut parts = line.splitn(2, ' ');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key, value.trim().to_string()),
+                _ => continue,
+            };
+
+            match key {
+                "hostname" => config.hostname = value,
+                "user" => config.user = value,
+                "port" => config.port = value.parse().unwrap_or(22),
+                "proxyjump" if value != "none" => config.proxy_jump = Some(value),
+                "identityfile" if identity_exists(&value) => config.identity_files.push(value),
+                _ => {}
+            }
+        }
+
+        config
+    }
+}
+
+fn identity_exists(path: &str) -> bool {
+    let path = match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => std::path::Path::new(&home).join(rest),
+        _ => path.into(),
+    };
+    path.exists()
+}
+
+impl fmt::Display for SshConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.user, self.hostname, self.port)?;
+        if let Some(jump) = &self.proxy_jump {
+            write!(f, " via {}", jump)?;
+        }
+        if !self.identity_files.is_empty() {
+            write!(f, " using {}", self.identity_files.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_ssh_config() {
+    use crate::testing::TempTree;
+    use std::fs;
+
+    let dir = TempTree::new("config");
+
+    let key = dir.join("prod_key");
+    fs::write(&key, "").unwrap();
+    let config = dir.join("config");
+    fs::write(
+        &config,
+        format!(
+            "Host prod-db\n  HostName 10.0.0.5\n  User deploy\n  Port 2222\n  ProxyJump bastion\n  IdentityFile {}\n",
+            key.display()
+        ),
+    )
+    .unwrap();
+
+    let options = vec!["-F".to_string(), config.to_str().unwrap().to_string()];
+    let resolved = match SshConfig::resolve(&options, "prod-db") {
+        Ok(resolved) => resolved,
+        // no ssh client installed
+        Err(RunnerError::Io(_)) | Err(RunnerError::NotFound(_)) => return,
+        Err(e) => panic!("{}", e),
+    };
+
+    assert_eq!(
+        resolved,
+        SshConfig {
+            hostname: "10.0.0.5".into(),
+            user: "deploy".into(),
+            port: 2222,
+            proxy_jump: Some("bastion".into()),
+            identity_files: vec![key.to_str().unwrap().into()],
+        }
+    );
+    assert_eq!(
+        resolved.to_string(),
+        format!("deploy@10.0.0.5:2222 via bastion using {}", key.display())
+    );
+
+    // the command line wins over the config
+    let resolved = SshConfig::resolve(&options, "admin@prod-db").unwrap();
+    assert_eq!(resolved.user, "admin");
+}
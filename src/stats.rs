@@ -0,0 +1,83 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Transfer counters for the whole mount, printed on unmount and in the
+/// spinners
+pub struct Stats {
+    /// file contents fetched
+    fetches: AtomicU64,
+    /// bytes of file contents handed to the kernel
+    content_bytes: AtomicU64,
+    /// bytes that actually came over the wire, after compression
+    wire_bytes: AtomicU64,
+}
+
+pub static STATS: Stats = Stats {
+    fetches: AtomicU64::new(0),
+    content_bytes: AtomicU64::new(0),
+    wire_bytes: AtomicU64::new(0),
+};
+
+impl Stats {
+    /// a fetch of `content` bytes that took `wire` bytes to transfer
+    pub fn record(&self, content: usize, wire: usize) {
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+        self.content_bytes
+            .fetch_add(content as u64, Ordering::Relaxed);
+        self.wire_bytes.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> String {
+        let content = self.content_bytes.load(Ordering::Relaxed);
+        let wire = self.wire_bytes.load(Ordering::Relaxed);
+
+        let mut summary = format!(
+            "{} fetches, {} read",
+            self.fetches.load(Ordering::Relaxed),
+            human_bytes(content)
+        );
+        if wire != content {
+            summary += &format!(
+                ", {} transferred ({:.0}%)",
+                human_bytes(wire),
+                wire as f64 * 100.0 / content.max(1) as f64
+            );
+        }
+        summary
+    }
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, units[unit])
+    }
+}
+
+#[test]
+fn test_stats() {
+    let stats = Stats {
+        fetches: AtomicU64::new(0),
+        content_bytes: AtomicU64::new(0),
+        wire_bytes: AtomicU64::new(0),
+    };
+
+    stats.record(1000, 1000);
+    assert_eq!(stats.summary(), "1 fetches, 1000B read");
+
+    stats.record(3 * 1024 * 1024, 1024 * 1024);
+    assert_eq!(
+        stats.summary(),
+        "2 fetches, 3.0MB read, 1.0MB transferred (33%)"
+    );
+
+    assert_eq!(human_bytes(1536), "1.5KB");
+}
@@ -0,0 +1,127 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::cmd::{CmdRunner, Listings};
+use crate::error::RunnerError;
+use crate::ls::FileMeta;
+
+/// A fresh directory under the temp dir, removed with everything in it
+/// when dropped, even if the test fails
+pub struct TempTree {
+    path: PathBuf,
+}
+
+impl TempTree {
+    /// `sshfuse-<name>-<pid>`, emptied if a previous run left it behind
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("sshfuse-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Deref for TempTree {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A CmdRunner passing every call on to `inner`, recording the method and
+/// paths of each in `calls`. contents go through `map_contents` on their
+/// way back, eg. to lose some bytes
+pub struct Forwarding<R> {
+    pub inner: R,
+    pub calls: Mutex<Vec<(&'static str, Vec<String>)>>,
+    pub map_contents: Box<dyn Fn(Vec<u8>) -> Vec<u8> + Send + Sync>,
+}
+
+impl<R: CmdRunner> Forwarding<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            calls: Default::default(),
+            map_contents: Box::new(|data| data),
+        }
+    }
+
+    pub fn with_map_contents(
+        mut self,
+        f: impl Fn(Vec<u8>) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.map_contents = Box::new(f);
+        self
+    }
+
+    /// the calls so far to `method`, by their paths
+    pub fn calls_to(&self, method: &str) -> Vec<Vec<String>> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(called, _)| *called == method)
+            .map(|(_, paths)| paths.clone())
+            .collect()
+    }
+
+    fn record(&self, method: &'static str, paths: &[&str]) {
+        let paths = paths.iter().map(|p| p.to_string()).collect();
+        self.calls.lock().unwrap().push((method, paths));
+    }
+}
+
+impl<R: CmdRunner> CmdRunner for Forwarding<R> {
+    fn fetch_path(&self, path: &str) -> Result<Vec<FileMeta>, RunnerError> {
+        self.record("fetch_path", &[path]);
+        self.inner.fetch_path(path)
+    }
+
+    fn fetch_file(&self, path: &str) -> Result<Vec<u8>, RunnerError> {
+        self.record("fetch_file", &[path]);
+        self.inner
+            .fetch_file(path)
+            .map(|data| (self.map_contents)(data))
+    }
+
+    fn fetch_range(&self, path: &str, offset: u64, len: u64) -> Result<Vec<u8>, RunnerError> {
+        self.record("fetch_range", &[path]);
+        self.inner
+            .fetch_range(path, offset, len)
+            .map(|data| (self.map_contents)(data))
+    }
+
+    fn fetch_paths(&self, paths: &[&str]) -> Listings {
+        self.record("fetch_paths", paths);
+        self.inner.fetch_paths(paths)
+    }
+
+    fn fetch_tree(&self, path: &str, depth: u32, max_entries: usize) -> Listings {
+        self.record("fetch_tree", &[path]);
+        self.inner.fetch_tree(path, depth, max_entries)
+    }
+
+    fn fetch_meta(&self, path: &str) -> Result<FileMeta, RunnerError> {
+        self.record("fetch_meta", &[path]);
+        self.inner.fetch_meta(path)
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.inner.queue_depth()
+    }
+}
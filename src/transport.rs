@@ -0,0 +1,356 @@
+use std::{fmt, process::Command, sync::Arc, time::Duration};
+
+use crate::askpass::Askpass;
+use crate::error::RunnerError;
+use crate::session::ControlMaster;
+use crate::shell;
+use crate::sshconfig::SshConfig;
+
+/// How shell commands reach the machine (or container) being mounted.
+/// `SshCmd` only needs a POSIX shell on the other side, so anything that
+/// can run one works.
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// a local process running shell command `cmd` on the other side. its
+    /// stdin and stdout are connected to the remote command
+    fn command(&self, cmd: &str) -> Command;
+
+    /// called after a failure that looks like the connection dropped
+    fn reset(&self) {}
+
+    /// what the mount is named after, eg. `user@host`
+    fn name(&self) -> String;
+}
+
+/// What `--target` points at: a ssh host (or `~/.ssh/config` alias), or a
+/// URL for another transport. the URL's path is the directory mounted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Ssh(String),
+    /// `docker://container/path`
+    Docker(String),
+    /// `k8s://namespace/pod/path`
+    Kubectl {
+        namespace: String,
+        pod: String,
+    },
+    /// `sh:///path`, commands run locally
+    Shell,
+}
+
+/// splits `--target` into where to connect and the directory to mount
+pub fn parse_target(target: &str) -> Result<(Target, String), String> {
+    let (scheme, rest) = match target.find("://") {
+        Some(i) => (&target[..i], &target[i + 3..]),
+        None => return Ok((Target::Ssh(target.into()), "/".into())),
+    };
+
+    // the path keeps its leading slash
+    let split_path = |s: &str| match s.find('/') {
+        Some(i) => (s[..i].to_string(), s[i..].to_string()),
+        None => (s.to_string(), "/".to_string()),
+    };
+
+    match scheme {
+        "docker" => {
+            let (container, root) = split_path(rest);
+            if container.is_empty() {
+                return Err(format!("no container in {}", target));
+            }
+            Ok((Target::Docker(container), root))
+        }
+        "k8s" => {
+            let (namespace, rest) = split_path(rest);
+            let (pod, root) = split_path(rest.trim_start_matches('/'));
+            if namespace.is_empty() || pod.is_empty() {
+                return Err(format!("expected k8s://namespace/pod/path, got {}", target));
+            }
+            Ok((Target::Kubectl { namespace, pod }, root))
+        }
+        "sh" => Ok((Target::Shell, split_path(rest).1)),
+        _ => Err(format!("unknown transport {}://", scheme)),
+    }
+}
+
+/// Runs commands over ssh, multiplexed over a managed ControlMaster
+#[derive(Debug)]
+pub struct SshTransport {
+    user: Option<String>,
+    target: String,
+    /// extra ssh arguments, already split into words
+    options: Vec<String>,
+    connect_timeout: Duration,
+    /// shared ssh connection all commands are multiplexed over
+    master: ControlMaster,
+    /// answers password prompts, when the user can be asked
+    askpass: Option<Arc<Askpass>>,
+}
+
+impl SshTransport {
+    /// `target` is a host name or a `~/.ssh/config` alias. without `user`,
+    /// ssh picks it (from the config, or the local user name)
+    pub fn new(user: Option<&str>, target: &str, options: &str) -> Self {
+        let mut ssh = Self {
+            user: user.map(String::from),
+            target: target.into(),
+            options: shell::split(options),
+            connect_timeout: Duration::from_secs(10),
+            master: ControlMaster::new(vec![]),
+            askpass: None,
+        };
+        ssh.master = ssh.new_master();
+        ssh
+    }
+
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self.master = self.new_master();
+        self
+    }
+
+    pub fn with_askpass(mut self, askpass: Askpass) -> Self {
+        self.askpass = Some(Arc::new(askpass));
+        self.master = self.new_master();
+        self
+    }
+
+    fn new_master(&self) -> ControlMaster {
+        let mut master_args = self.connect_options();
+        master_args.extend(self.options.iter().cloned());
+        master_args.push(self.destination());
+
+        let master = ControlMaster::new(master_args).with_timeout(self.connect_timeout);
+        match &self.askpass {
+            Some(askpass) => master.with_askpass(askpass.clone()),
+            None => master,
+        }
+    }
+
+    /// ssh options bounding how long connecting may take. they come before
+    /// the user's options, which win as ssh keeps the first value it sees
+    fn connect_options(&self) -> Vec<String> {
+        vec![
+            "-o".into(),
+            format!("ConnectTimeout={}", self.connect_timeout.as_secs().max(1)),
+        ]
+    }
+
+    /// the settings ssh will connect to the target with
+    pub fn resolve_config(&self) -> Result<SshConfig, RunnerError> {
+        SshConfig::resolve(&self.options, &self.destination())
+    }
+
+    /// eg. `user@host` or a config alias
+    pub fn destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.target),
+            None => self.target.clone(),
+        }
+    }
+
+    /// a ssh command that starts `subsystem` (eg. `sftp`) on the target
+    /// instead of running a shell command
+    pub fn subsystem_command(&self, subsystem: &str) -> Command {
+        if let Err(e) = self.master.ensure() {
+            println!("ssh master unavailable: {}", e);
+        }
+
+        let mut cmd = Command::new("ssh");
+        cmd.args(self.master.client_options())
+            .args(self.connect_options())
+            .args(&self.options)
+            .arg("-s")
+            .arg(self.destination())
+            .arg(subsystem);
+        if let Some(askpass) = &self.askpass {
+            askpass.apply(&mut cmd);
+        }
+        cmd
+    }
+
+    /// the local ssh invocation running `cmd` on the target. ssh options
+    /// are passed as separate argv entries, `cmd` goes to the remote shell
+    /// as is so anything variable in it must already be quoted
+    fn get_full_cmd(&self, cmd: &str) -> Command {
+        let mut ssh = Command::new("ssh");
+        ssh.args(self.master.client_options())
+            .args(self.connect_options())
+            .args(&self.options)
+            .arg(self.destination())
+            .arg("--")
+            .arg(cmd);
+        // for when there's no master to go through
+        if let Some(askpass) = &self.askpass {
+            askpass.apply(&mut ssh);
+        }
+        ssh
+    }
+}
+
+impl Transport for SshTransport {
+    fn command(&self, cmd: &str) -> Command {
+        // without a master the command still runs, just on its own connection
+        if let Err(e) = self.master.ensure() {
+            println!("ssh master unavailable: {}", e);
+        }
+
+        self.get_full_cmd(cmd)
+    }
+
+    fn reset(&self) {
+        // the master may have gone down with the connection
+        self.master.reset();
+    }
+
+    fn name(&self) -> String {
+        self.destination()
+    }
+}
+
+/// Runs commands in a running container with `docker exec`
+#[derive(Debug)]
+pub struct DockerTransport {
+    container: String,
+}
+
+impl DockerTransport {
+    pub fn new(container: &str) -> Self {
+        Self {
+            container: container.into(),
+        }
+    }
+}
+
+impl Transport for DockerTransport {
+    fn command(&self, cmd: &str) -> Command {
+        let mut docker = Command::new("docker");
+        docker
+            .args(&["exec", "-i"])
+            .arg(&self.container)
+            .args(&["sh", "-c"])
+            .arg(cmd);
+        docker
+    }
+
+    fn name(&self) -> String {
+        format!("docker://{}", self.container)
+    }
+}
+
+/// Runs commands in a pod's (first) container with `kubectl exec`
+#[derive(Debug)]
+pub struct KubectlTransport {
+    namespace: String,
+    pod: String,
+}
+
+impl KubectlTransport {
+    pub fn new(namespace: &str, pod: &str) -> Self {
+        Self {
+            namespace: namespace.into(),
+            pod: pod.into(),
+        }
+    }
+}
+
+impl Transport for KubectlTransport {
+    fn command(&self, cmd: &str) -> Command {
+        let mut kubectl = Command::new("kubectl");
+        kubectl
+            .args(&["exec", "-i", "-n"])
+            .arg(&self.namespace)
+            .arg(&self.pod)
+            .args(&["--", "sh", "-c"])
+            .arg(cmd);
+        kubectl
+    }
+
+    fn name(&self) -> String {
+        format!("k8s://{}/{}", self.namespace, self.pod)
+    }
+}
+
+/// Runs commands with the local `sh`, mostly for testing the whole stack
+/// without a remote
+#[derive(Debug)]
+pub struct ShellTransport;
+
+impl Transport for ShellTransport {
+    fn command(&self, cmd: &str) -> Command {
+        let mut sh = Command::new("sh");
+        sh.arg("-c").arg(cmd);
+        sh
+    }
+
+    fn name(&self) -> String {
+        "sh://".into()
+    }
+}
+
+#[test]
+fn test_parse_target() {
+    assert_eq!(
+        parse_target("prod-db").unwrap(),
+        (Target::Ssh("prod-db".into()), "/".into())
+    );
+    assert_eq!(
+        parse_target("docker://web").unwrap(),
+        (Target::Docker("web".into()), "/".into())
+    );
+    assert_eq!(
+        parse_target("docker://web/var/log").unwrap(),
+        (Target::Docker("web".into()), "/var/log".into())
+    );
+    assert_eq!(
+        parse_target("k8s://default/api-7d9f/etc").unwrap(),
+        (
+            Target::Kubectl {
+                namespace: "default".into(),
+                pod: "api-7d9f".into()
+            },
+            "/etc".into()
+        )
+    );
+    assert_eq!(
+        parse_target("sh:///srv/root").unwrap(),
+        (Target::Shell, "/srv/root".into())
+    );
+    assert!(parse_target("k8s://default").is_err());
+    assert!(parse_target("docker:///x").is_err());
+    assert!(parse_target("ftp://host").is_err());
+}
+
+#[test]
+fn test_transports() {
+    let ssh = SshTransport::new(Some("me"), "host", "-p 2222 -i 'my key'");
+    let cmd = ssh.get_full_cmd("cat -- '/tmp/a b'");
+    let args = cmd
+        .get_args()
+        .map(|a| a.to_str().unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(cmd.get_program(), "ssh");
+    assert!(args.contains(&"ConnectTimeout=10"));
+    assert_eq!(
+        &args[args.len() - 7..],
+        &[
+            "-p",
+            "2222",
+            "-i",
+            "my key",
+            "me@host",
+            "--",
+            "cat -- '/tmp/a b'"
+        ]
+    );
+
+    // a config alias goes as is
+    let ssh = SshTransport::new(None, "prod-db", "");
+    assert_eq!(ssh.name(), "prod-db");
+
+    let cmd = DockerTransport::new("web").command("ls -l -- /");
+    let args = cmd
+        .get_args()
+        .map(|a| a.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(args, &["exec", "-i", "web", "sh", "-c", "ls -l -- /"]);
+}